@@ -0,0 +1,106 @@
+//! Throughput benchmarks for encoding and decoding WU8 files, run with `cargo bench`.
+//!
+//! `xor/scalar` and `xor/words` compare a byte-at-a-time XOR against the word-wise XOR used for pass two.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use wbz_converter::U8Builder;
+
+/// The minimum time spent running each benchmark.
+const TARGET_DURATION: Duration = Duration::from_secs(2);
+
+/// Builds a U8 archive of `files` files with `file_size` bytes each, spread across directories nested `depth` deep.
+fn build_archive(files: usize, depth: usize, file_size: usize) -> Vec<u8> {
+    let mut builder = U8Builder::new();
+    for index in 0..files {
+        let mut path = String::from(".");
+        for level in 0..(index % depth) {
+            path.push_str(&format!("/dir{level}"));
+        }
+
+        path.push_str(&format!("/file{index}.bin"));
+        let data: Vec<u8> = (0..file_size)
+            .map(|byte| (byte * 31 + index) as u8)
+            .collect();
+        builder.add_file(&path, data);
+    }
+
+    builder.finish().unwrap()
+}
+
+/// Runs `routine` on a fresh copy of `input` until [`TARGET_DURATION`] has been spent, then prints the throughput.
+fn bench(name: &str, input: &[u8], mut routine: impl FnMut(&mut Vec<u8>)) {
+    let mut iterations = 0_u32;
+    let mut elapsed = Duration::ZERO;
+    while elapsed < TARGET_DURATION {
+        let mut file = input.to_vec();
+
+        let start = Instant::now();
+        routine(black_box(&mut file));
+        elapsed += start.elapsed();
+
+        black_box(file);
+        iterations += 1;
+    }
+
+    let per_iteration = elapsed / iterations;
+    let throughput = input.len() as f64 / per_iteration.as_secs_f64() / 1_000_000.0;
+    println!("{name:<24} {per_iteration:>12.2?}/iter {throughput:>10.1} MB/s");
+}
+
+/// XORs every byte of `data` with `key`, one byte at a time.
+fn xor_scalar(data: &mut [u8], key: u8) {
+    for byte in data {
+        *byte ^= key;
+    }
+}
+
+/// XORs every byte of `data` with `key`, eight bytes at a time, matching the pass two implementation.
+fn xor_words(data: &mut [u8], key: u8) {
+    let wide_key = u64::from_ne_bytes([key; 8]);
+
+    let mut words = data.chunks_exact_mut(8);
+    for word in &mut words {
+        let value = u64::from_ne_bytes([
+            word[0], word[1], word[2], word[3], word[4], word[5], word[6], word[7],
+        ]);
+
+        word.copy_from_slice(&(value ^ wide_key).to_ne_bytes());
+    }
+
+    for byte in words.into_remainder() {
+        *byte ^= key;
+    }
+}
+
+fn main() {
+    // Pass two XORs most of the file with a single key, so is compared against the obvious byte loop.
+    let data: Vec<u8> = (0..16 * 1024 * 1024).map(|byte| byte as u8).collect();
+    bench("xor/scalar", &data, |data| {
+        xor_scalar(data, black_box(0xA5))
+    });
+    bench("xor/words", &data, |data| xor_words(data, black_box(0xA5)));
+
+    let archives = [
+        ("small", build_archive(16, 1, 1024)),
+        ("medium", build_archive(256, 4, 16 * 1024)),
+        ("large", build_archive(64, 2, 1024 * 1024)),
+        ("deep", build_archive(256, 32, 1024)),
+    ];
+
+    for (name, u8_file) in &archives {
+        let mut wu8_file = u8_file.clone();
+        wbz_converter::encode_wu8(&mut wu8_file, None).unwrap();
+
+        bench(&format!("encode_wu8/{name}"), u8_file, |file| {
+            wbz_converter::encode_wu8(file, None).unwrap();
+        });
+
+        bench(&format!("decode_wu8/{name}"), &wu8_file, |file| {
+            wbz_converter::decode_wu8(file, None).unwrap();
+        });
+    }
+}
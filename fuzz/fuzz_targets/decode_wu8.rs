@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wbz_converter::ConversionOptions;
+
+fuzz_target!(|data: &[u8]| {
+    let mut wu8_file = data.to_vec();
+    let _ = wbz_converter::decode_wu8(&mut wu8_file, None);
+
+    // Treat every file as part of the auto-add library, to also exercise the first pass.
+    let resolver = |path: &std::path::Path| Ok(Some(path.as_os_str().as_encoded_bytes().to_vec()));
+    let mut wu8_file = data.to_vec();
+    let _ = wbz_converter::decode_wu8_with(
+        &mut wu8_file,
+        ConversionOptions::new().with_resolver(&resolver),
+    );
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wbz_converter::{decode_wu8, encode_wu8, U8Builder};
+
+fuzz_target!(|data: &[u8]| {
+    // Only valid archives round-trip, so build one with the input choosing each file's directory and contents.
+    let mut builder = U8Builder::new();
+    for (index, chunk) in data.chunks(16).enumerate() {
+        let dir: String = (0..chunk[0] % 8)
+            .map(|depth| format!("/dir{depth}"))
+            .collect();
+        builder.add_file(&format!(".{dir}/file{index}"), &chunk[1..]);
+    }
+
+    let Ok(u8_file) = builder.finish() else {
+        return;
+    };
+
+    let mut wu8_file = u8_file.clone();
+    encode_wu8(&mut wu8_file, None).unwrap();
+    decode_wu8(&mut wu8_file, None).unwrap();
+    assert_eq!(wu8_file, u8_file);
+});
@@ -0,0 +1,491 @@
+use crate::{
+    iterator::{U8Entry, U8Iterator, U8Walker},
+    parser::read_checked_header,
+    passes::{derive_starting_key, perform_header_pass},
+    Error, U8Node, U8_MAGIC, WU8_MAGIC,
+};
+
+/// The raw metadata of a single node in a U8 archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub is_dir: bool,
+    pub name: String,
+    /// For files, the offset of the file data. For directories, the index of the parent directory.
+    pub data_offset: u32,
+    /// For files, the size of the file data. For directories, the index after the last child node.
+    pub size: u32,
+}
+
+/// Parses a U8 file, returning the metadata of every node in node table order, including the root.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, see [`U8Archive::new`].
+pub fn node_info(u8_file: &[u8]) -> Result<Vec<NodeInfo>, Error> {
+    U8Archive::new(u8_file).map(|archive| archive.node_info().collect())
+}
+
+/// A single record of the node table, without its name, see [`scan_u8`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeRecord {
+    pub is_dir: bool,
+    /// The offset of the node's name within the string table.
+    pub name_offset: u32,
+    /// For files, the offset of the file data. For directories, the index of the parent directory.
+    pub data_offset: u32,
+    /// For files, the size of the file data. For directories, the index after the last child node.
+    pub size: u32,
+}
+
+/// The node table of a U8 archive, read without the string table, see [`scan_u8`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeScan {
+    /// Every node in node table order, including the root.
+    pub nodes: Vec<NodeRecord>,
+}
+
+impl NodeScan {
+    /// Returns the number of files in the archive.
+    #[must_use]
+    pub fn file_count(&self) -> usize {
+        self.nodes.iter().filter(|node| !node.is_dir).count()
+    }
+
+    /// Returns the number of directories in the archive, excluding the root.
+    #[must_use]
+    pub fn dir_count(&self) -> usize {
+        self.nodes.iter().skip(1).filter(|node| node.is_dir).count()
+    }
+
+    /// Returns the combined size of every file's data.
+    #[must_use]
+    pub fn data_size(&self) -> u64 {
+        self.nodes
+            .iter()
+            .filter(|node| !node.is_dir)
+            .map(|node| u64::from(node.size))
+            .sum()
+    }
+}
+
+/// Parses a U8 file, reading only the 12 byte records of the node table.
+///
+/// Unlike [`node_info`], names are not read from the string table and file data is not checked,
+/// so this is much faster when only counts or offsets are needed, such as when indexing many archives.
+///
+/// # Errors
+/// Errors if the file does not start with a valid U8 header, or the node table is truncated.
+pub fn scan_u8(u8_file: &[u8]) -> Result<NodeScan, Error> {
+    let mut walker = u8_walker(u8_file)?;
+
+    let mut nodes = Vec::with_capacity(walker.node_count() as usize);
+    while let Some(next) = walker.next_node(u8_file) {
+        let (index, node) = next?;
+        node.check_dir_size(index, walker.node_count())
+            .map_err(|err| Error::AtNode {
+                index,
+                name: None,
+                source: Box::new(err),
+            })?;
+
+        nodes.push(NodeRecord {
+            is_dir: node.is_dir,
+            name_offset: node.name_offset.into(),
+            data_offset: node.data_offset,
+            size: node.size,
+        });
+    }
+
+    Ok(NodeScan { nodes })
+}
+
+/// An entry in the listing of a U8 archive, see [`list_u8`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// The full path of the entry, see [`U8Archive`].
+    pub path: String,
+    pub is_dir: bool,
+    /// For files, the offset of the file data. For directories, the index of the parent directory.
+    pub offset: u32,
+    /// For files, the size of the file data. For directories, the index after the last child node.
+    pub size: u32,
+}
+
+impl From<U8Entry> for EntryInfo {
+    fn from(entry: U8Entry) -> Self {
+        let node = entry.node;
+        Self {
+            path: entry.into_path_string(),
+            is_dir: node.is_dir,
+            offset: node.data_offset,
+            size: node.size,
+        }
+    }
+}
+
+impl EntryInfo {
+    /// Returns the data of this file within `file`, which is still encoded if `file` is a WU8 file.
+    ///
+    /// Returns `None` for directories, or if the data lies outside of `file`.
+    #[must_use]
+    pub fn raw_data<'a>(&self, file: &'a [u8]) -> Option<&'a [u8]> {
+        if self.is_dir {
+            return None;
+        }
+
+        let start = self.offset as usize;
+        file.get(start..start + self.size as usize)
+    }
+}
+
+/// Parses a U8 file, returning the path and location of every entry in node table order, excluding the root.
+///
+/// This does not read the auto-add library, so is much cheaper than decoding the file.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, see [`U8Archive::new`], stopping at the first invalid entry.
+/// Use [`iter_u8`] to continue past invalid entries.
+pub fn list_u8(u8_file: &[u8]) -> Result<Vec<EntryInfo>, Error> {
+    iter_u8(u8_file)?.collect()
+}
+
+/// Parses the header of a U8 file, returning an iterator over the path and location of every entry
+/// in node table order, excluding the root.
+///
+/// Unlike [`list_u8`], an invalid entry is returned as an error without stopping the iterator,
+/// so the caller can decide whether to skip it. If a directory's name is invalid, its children are skipped.
+///
+/// # Errors
+/// Errors if the file does not start with a valid U8 header and root node.
+pub fn iter_u8(u8_file: &[u8]) -> Result<EntryIter<'_>, Error> {
+    let walker = u8_walker(u8_file)?;
+    Ok(EntryIter {
+        entries: U8Iterator::new(u8_file, walker),
+    })
+}
+
+/// An iterator over the entries of a U8 archive, see [`iter_u8`].
+pub struct EntryIter<'a> {
+    entries: U8Iterator<'a>,
+}
+
+impl Iterator for EntryIter<'_> {
+    type Item = Result<EntryInfo, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            return match self.entries.next()? {
+                Ok(entry) if entry.index == 0 => continue,
+                Ok(entry) => Some(Ok(entry.into())),
+                Err(err) => Some(Err(err)),
+            };
+        }
+    }
+}
+
+/// Reads the header and root node of a U8 file, returning a walker over its node table.
+fn u8_walker(u8_file: &[u8]) -> Result<U8Walker, Error> {
+    let header = read_checked_header(u8_file, U8_MAGIC)?;
+    U8Walker::new(u8_file, &header, u8_file.len())
+}
+
+/// Parses a WU8 file, returning the path and location of every entry in node table order, excluding the root.
+///
+/// Only the node and string tables are decoded, which do not depend on the auto-add library,
+/// so this never reads from disk.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, see [`U8Archive::new`].
+pub fn list_wu8(wu8_file: &[u8]) -> Result<Vec<EntryInfo>, Error> {
+    let header = read_checked_header(wu8_file, WU8_MAGIC)?;
+
+    // The header is kept so the node and string tables stay at the offsets given by it.
+    let mut meta = wu8_file[..header.meta_range().end].to_vec();
+    let starting_key = derive_starting_key(wu8_file.len().try_into()?);
+    perform_header_pass(
+        &mut meta,
+        starting_key,
+        header.node_offset,
+        header.meta_size,
+    );
+
+    let walker = U8Walker::new(&meta, &header, wu8_file.len())?;
+    EntryIter {
+        entries: U8Iterator::new(&meta, walker),
+    }
+    .collect()
+}
+
+/// Parses a U8 file, rendering every entry as an indented listing similar to `tree`.
+///
+/// Directories are suffixed with `/` and their children indented below them, and files include their size in bytes.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, see [`U8Archive::new`].
+pub fn format_tree(u8_file: &[u8]) -> Result<String, Error> {
+    let archive = U8Archive::new(u8_file)?;
+
+    let mut out = String::new();
+    for entry in archive.entries() {
+        let indent = "  ".repeat(entry.depth());
+        let name = entry.path().rsplit('/').next().unwrap_or_default();
+
+        let line = match entry {
+            Entry::File { data, .. } => format!("{indent}{name} ({} bytes)\n", data.len()),
+            Entry::Dir(_) => format!("{indent}{name}/\n"),
+        };
+
+        out.push_str(&line);
+    }
+
+    Ok(out)
+}
+
+/// A parsed U8 archive, allowing random access to the contained files.
+///
+/// Paths are the names of each parent directory joined with `/`, not including the unnamed root,
+/// such as `./course_model.brres`.
+pub struct U8Archive<'a> {
+    file: &'a [u8],
+    nodes: Vec<U8Entry>,
+}
+
+impl<'a> U8Archive<'a> {
+    /// Parses the node and string tables of a U8 file.
+    ///
+    /// # Errors
+    /// Errors if the file is an invalid U8 file, which includes invalid magic, a truncated file,
+    /// or file data that lies outside of the file.
+    ///
+    /// See [`Error`] for all possible failure states.
+    pub fn new(file: &'a [u8]) -> Result<Self, Error> {
+        let walker = u8_walker(file)?;
+        let nodes = U8Iterator::new(file, walker).collect::<Result<_, _>>()?;
+
+        Ok(Self { file, nodes })
+    }
+
+    /// Returns the root directory of the archive.
+    #[must_use]
+    pub fn root(&self) -> Dir<'_> {
+        Dir {
+            archive: self,
+            index: 0,
+        }
+    }
+
+    /// Returns every entry in the archive in node table order, excluding the root directory.
+    pub fn entries(&self) -> impl Iterator<Item = Entry<'_>> {
+        (1..self.nodes.len()).map(|index| self.entry(index))
+    }
+
+    /// Returns the metadata of every node in node table order, including the root.
+    pub fn node_info(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.nodes.iter().map(|node| NodeInfo {
+            is_dir: node.node.is_dir,
+            name: node.name().to_owned(),
+            data_offset: node.node.data_offset,
+            size: node.node.size,
+        })
+    }
+
+    /// Returns the data of the file at `path`, or `None` if there is no file at that path.
+    #[must_use]
+    pub fn get(&self, path: &str) -> Option<&[u8]> {
+        self.nodes
+            .iter()
+            .find(|node| !node.node.is_dir && node.path_str() == path)
+            .map(|node| self.data(&node.node))
+    }
+
+    fn data(&self, node: &U8Node) -> &[u8] {
+        let start = node.data_offset as usize;
+        &self.file[start..start + node.size as usize]
+    }
+
+    fn entry(&self, index: usize) -> Entry<'_> {
+        let node = &self.nodes[index];
+        if node.node.is_dir {
+            Entry::Dir(Dir {
+                archive: self,
+                index,
+            })
+        } else {
+            Entry::File {
+                path: node.path_str(),
+                data: self.data(&node.node),
+            }
+        }
+    }
+}
+
+/// An entry in a [`U8Archive`].
+#[derive(Clone, Copy)]
+pub enum Entry<'a> {
+    File { path: &'a str, data: &'a [u8] },
+    Dir(Dir<'a>),
+}
+
+impl<'a> Entry<'a> {
+    /// Returns the full path of this entry.
+    #[must_use]
+    pub fn path(&self) -> &'a str {
+        match self {
+            Self::File { path, .. } => path,
+            Self::Dir(dir) => dir.path(),
+        }
+    }
+
+    /// Returns the number of directories this entry is nested in, not including the unnamed root.
+    ///
+    /// Entries directly inside the root, such as the usual `.` directory, have a depth of 0.
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        path_depth(self.path())
+    }
+}
+
+/// A directory in a [`U8Archive`].
+#[derive(Clone, Copy)]
+pub struct Dir<'a> {
+    archive: &'a U8Archive<'a>,
+    index: usize,
+}
+
+impl<'a> Dir<'a> {
+    /// Returns the full path of this directory.
+    #[must_use]
+    pub fn path(&self) -> &'a str {
+        self.archive.nodes[self.index].path_str()
+    }
+
+    /// Returns the name of this directory, without its parents.
+    #[must_use]
+    pub fn name(&self) -> &'a str {
+        self.archive.nodes[self.index].name()
+    }
+
+    /// Returns the number of directories this directory is nested in, see [`Entry::depth`].
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        path_depth(self.path())
+    }
+
+    /// Returns the direct children of this directory.
+    pub fn entries(&self) -> impl Iterator<Item = Entry<'a>> {
+        let archive = self.archive;
+        let end = archive.nodes[self.index].node.size as usize;
+
+        let mut index = self.index + 1;
+        std::iter::from_fn(move || {
+            if index >= end.min(archive.nodes.len()) {
+                return None;
+            }
+
+            let node = &archive.nodes[index].node;
+            let entry = archive.entry(index);
+            index = if node.is_dir {
+                (node.size as usize).max(index + 1)
+            } else {
+                index + 1
+            };
+
+            Some(entry)
+        })
+    }
+}
+
+/// Extracts every [BRRES](https://wiki.tockdom.com/wiki/BRRES_(File_Format)) file from a U8 archive.
+pub struct BrresExtractor<'a> {
+    archive: U8Archive<'a>,
+}
+
+impl<'a> BrresExtractor<'a> {
+    /// Parses a U8 file, such as the output of [`decode_wbz`](crate::decode_wbz), to extract BRRES files from.
+    ///
+    /// # Errors
+    /// Errors if the file is an invalid U8 file, see [`U8Archive::new`].
+    pub fn new(u8_file: &'a [u8]) -> Result<Self, Error> {
+        U8Archive::new(u8_file).map(|archive| Self { archive })
+    }
+
+    /// Returns the full path and data of every file with a `.brres` extension.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.archive.entries().filter_map(|entry| match entry {
+            Entry::File { path, data } if is_brres(path) => Some((path, data)),
+            _ => None,
+        })
+    }
+}
+
+fn path_depth(path: &str) -> usize {
+    path.matches('/').count()
+}
+
+fn is_brres(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("brres"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_wu8, U8Builder};
+
+    fn sample_archive() -> Vec<u8> {
+        U8Builder::new()
+            .add_file("./course.kmp", *b"kmp")
+            .add_file("./posteffect/posteffect.bfg", *b"bfg")
+            .add_file("./course_model.brres", *b"brres")
+            .finish()
+            .unwrap()
+    }
+
+    #[test]
+    fn walkers_agree() {
+        let u8_file = sample_archive();
+
+        let listed: Vec<_> = list_u8(&u8_file).unwrap();
+        let archive = U8Archive::new(&u8_file).unwrap();
+        let archive_paths: Vec<_> = archive.entries().map(|entry| entry.path()).collect();
+        let listed_paths: Vec<_> = listed.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(listed_paths, archive_paths);
+        assert_eq!(
+            listed_paths,
+            [
+                ".",
+                "./course.kmp",
+                "./posteffect",
+                "./posteffect/posteffect.bfg",
+                "./course_model.brres"
+            ]
+        );
+
+        let scan = scan_u8(&u8_file).unwrap();
+        assert_eq!(scan.nodes.len(), listed.len() + 1);
+        assert_eq!(scan.file_count(), 3);
+        assert_eq!(scan.dir_count(), 2);
+
+        let mut wu8_file = u8_file.clone();
+        encode_wu8(&mut wu8_file, None).unwrap();
+        assert_eq!(list_wu8(&wu8_file).unwrap(), listed);
+    }
+
+    #[test]
+    fn walkers_reject_out_of_bounds_data() {
+        let mut u8_file = sample_archive();
+        let data_end = list_u8(&u8_file).unwrap().last().unwrap().offset as usize;
+        u8_file.truncate(data_end);
+
+        let is_out_of_bounds = |err: Error| {
+            matches!(
+                err,
+                Error::AtNode { index: 5, name: Some(name), source }
+                    if name == "course_model.brres" && matches!(*source, Error::NodeOutOfBounds { .. })
+            )
+        };
+
+        assert!(is_out_of_bounds(list_u8(&u8_file).unwrap_err()));
+        assert!(is_out_of_bounds(U8Archive::new(&u8_file).err().unwrap()));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::{Endianness, Error, U8_MAGIC};
+
+const HEADER_SIZE: usize = 0x20;
+const NODE_SIZE: usize = 12;
+const DEFAULT_ALIGNMENT: u32 = 0x20;
+const MAX_NAME_OFFSET: usize = 0xFF_FFFF;
+
+enum BuilderNode {
+    File {
+        name: String,
+        data: Vec<u8>,
+    },
+    Dir {
+        name: String,
+        children: Vec<BuilderNode>,
+    },
+}
+
+impl BuilderNode {
+    fn name(&self) -> &str {
+        match self {
+            Self::File { name, .. } | Self::Dir { name, .. } => name,
+        }
+    }
+}
+
+struct FlatNode<'a> {
+    is_dir: bool,
+    name_offset: u32,
+    data: &'a [u8],
+    data_offset: u32,
+    size: u32,
+}
+
+/// Builds a U8 archive from scratch, such as for encoding with [`encode_wbz`](crate::encode_wbz).
+///
+/// Paths use the same format as [`U8Archive`](crate::U8Archive), with each directory name joined by `/`,
+/// such as `./course.kmp`. Missing parent directories are created automatically, and entries are
+/// written in the order they were first added.
+pub struct U8Builder {
+    root: Vec<BuilderNode>,
+    alignment: u32,
+    endianness: Endianness,
+}
+
+impl Default for U8Builder {
+    fn default() -> Self {
+        Self {
+            root: Vec::new(),
+            alignment: DEFAULT_ALIGNMENT,
+            endianness: Endianness::Big,
+        }
+    }
+}
+
+impl U8Builder {
+    /// Creates a builder for an archive containing only the root directory.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the alignment of each file's data, defaulting to 32 bytes.
+    ///
+    /// The gaps between files are filled with zeros, and an alignment of `0` or `1` packs files together.
+    pub fn alignment(&mut self, alignment: u32) -> &mut Self {
+        self.alignment = alignment.max(1);
+        self
+    }
+
+    /// Sets the byte order of the header and node table fields, defaulting to big-endian.
+    ///
+    /// Little-endian archives cannot be compressed into a WBZ file, see [`Endianness`].
+    pub fn endianness(&mut self, endianness: Endianness) -> &mut Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Adds an empty directory at `path`, replacing any file already at that path.
+    pub fn add_dir(&mut self, path: &str) -> &mut Self {
+        dir_mut(&mut self.root, path);
+        self
+    }
+
+    /// Adds a file containing `data` at `path`, replacing any entry already at that path.
+    pub fn add_file(&mut self, path: &str, data: impl Into<Vec<u8>>) -> &mut Self {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let file = BuilderNode::File {
+            name: name.to_owned(),
+            data: data.into(),
+        };
+
+        let children = dir_mut(&mut self.root, parent);
+        match children.iter().position(|node| node.name() == name) {
+            Some(index) => children[index] = file,
+            None => children.push(file),
+        }
+
+        self
+    }
+
+    /// Lays out the node table, string table, and file data into a U8 file.
+    ///
+    /// # Errors
+    /// Errors if the archive would be above 4GB in size, or the string table above 16MB.
+    pub fn finish(&self) -> Result<Vec<u8>, Error> {
+        let mut strings = vec![0];
+        let mut nodes = vec![FlatNode {
+            is_dir: true,
+            name_offset: 0,
+            data: &[],
+            data_offset: 0,
+            size: 0,
+        }];
+
+        flatten(&self.root, 0, &mut nodes, &mut strings)?;
+        nodes[0].size = nodes.len().try_into()?;
+
+        let meta_size = nodes.len() * NODE_SIZE + strings.len();
+        let data_start = self.align(HEADER_SIZE + meta_size);
+
+        let mut data_end = data_start;
+        for node in nodes.iter_mut().filter(|node| !node.is_dir) {
+            let data_offset = self.align(data_end);
+            node.data_offset = data_offset.try_into()?;
+            data_end = data_offset + node.data.len();
+        }
+
+        let endianness = self.endianness;
+        let mut out = Vec::with_capacity(data_end);
+        out.extend_from_slice(&endianness.magic(U8_MAGIC));
+        out.extend_from_slice(&endianness.u32_to_bytes(HEADER_SIZE.try_into()?));
+        out.extend_from_slice(&endianness.u32_to_bytes(meta_size.try_into()?));
+        out.extend_from_slice(&endianness.u32_to_bytes(data_start.try_into()?));
+        out.resize(HEADER_SIZE, 0);
+
+        for node in &nodes {
+            let type_and_name = u32::from(node.is_dir) << 24 | node.name_offset;
+            out.extend_from_slice(&endianness.u32_to_bytes(type_and_name));
+            out.extend_from_slice(&endianness.u32_to_bytes(node.data_offset));
+            out.extend_from_slice(&endianness.u32_to_bytes(node.size));
+        }
+
+        out.extend_from_slice(&strings);
+        out.resize(data_start, 0);
+        for node in nodes.iter().filter(|node| !node.is_dir) {
+            out.resize(node.data_offset as usize, 0);
+            out.extend_from_slice(node.data);
+        }
+
+        Ok(out)
+    }
+
+    fn align(&self, offset: usize) -> usize {
+        offset.next_multiple_of(self.alignment as usize)
+    }
+}
+
+/// Returns the children of the directory at `path`, creating it and any parents if missing.
+fn dir_mut<'a>(mut children: &'a mut Vec<BuilderNode>, path: &str) -> &'a mut Vec<BuilderNode> {
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        let dir = BuilderNode::Dir {
+            name: component.to_owned(),
+            children: Vec::new(),
+        };
+
+        let index = if let Some(index) = children.iter().position(|node| node.name() == component) {
+            if let BuilderNode::File { .. } = children[index] {
+                children[index] = dir;
+            }
+
+            index
+        } else {
+            children.push(dir);
+            children.len() - 1
+        };
+
+        children = match &mut children[index] {
+            BuilderNode::Dir { children, .. } => children,
+            BuilderNode::File { .. } => unreachable!("node was just replaced with a directory"),
+        };
+    }
+
+    children
+}
+
+/// Appends `children` to the node table in depth first order, writing their names to the string table.
+fn flatten<'a>(
+    children: &'a [BuilderNode],
+    parent: u32,
+    nodes: &mut Vec<FlatNode<'a>>,
+    strings: &mut Vec<u8>,
+) -> Result<(), Error> {
+    for child in children {
+        if strings.len() > MAX_NAME_OFFSET {
+            return Err(Error::StringTableTooBig);
+        }
+
+        let name_offset = strings.len().try_into()?;
+        strings.extend_from_slice(child.name().as_bytes());
+        strings.push(0);
+
+        match child {
+            BuilderNode::File { data, .. } => nodes.push(FlatNode {
+                is_dir: false,
+                name_offset,
+                data,
+                data_offset: 0,
+                size: data.len().try_into()?,
+            }),
+            BuilderNode::Dir { children, .. } => {
+                let index = nodes.len();
+                nodes.push(FlatNode {
+                    is_dir: true,
+                    name_offset,
+                    data: &[],
+                    data_offset: parent,
+                    size: 0,
+                });
+
+                flatten(children, index.try_into()?, nodes, strings)?;
+                nodes[index].size = nodes.len().try_into()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_wu8, encode_wu8, list_u8};
+
+    #[test]
+    fn aligned_round_trip_keeps_padding() {
+        let mut u8_file = U8Builder::new()
+            .alignment(0x20)
+            .add_file("./course.kmp", *b"kmp")
+            .add_file("./course_model.brres", *b"brres")
+            .finish()
+            .unwrap();
+
+        let data_offsets: Vec<usize> = list_u8(&u8_file)
+            .unwrap()
+            .into_iter()
+            .filter(|entry| !entry.is_dir)
+            .map(|entry| entry.offset as usize)
+            .collect();
+
+        assert!(data_offsets.iter().all(|offset| offset % 0x20 == 0));
+        assert_eq!(data_offsets[1] - data_offsets[0], 0x20);
+
+        // Fill the padding after the first file, which is not part of any node.
+        let padding = data_offsets[0] + 3..data_offsets[1];
+        u8_file[padding.clone()].fill(0xAA);
+
+        let mut wu8_file = u8_file.clone();
+        encode_wu8(&mut wu8_file, None).unwrap();
+        assert_eq!(wu8_file[padding.clone()], u8_file[padding]);
+
+        decode_wu8(&mut wu8_file, None).unwrap();
+        assert_eq!(wu8_file, u8_file);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    io::{Read, Seek},
+    path::Path,
+};
+
+use crate::{
+    converter::{Converter, ConverterBuffers, StepResult},
+    decompress_wbz_into,
+    iterator::{AutoAdd, AutoAddCache},
+    Error, PassOptions,
+};
+
+/// Reusable state for converting many files with the same auto-add library.
+///
+/// The context keeps the allocations made by each conversion, such as the decompressed file, the copy of the
+/// node table, and the path of each file, so converting a batch of tracks does not allocate them again.
+///
+/// The context can also cache the auto-add files read by each conversion, see [`Self::with_autoadd_cache`].
+#[derive(Default)]
+pub struct ConversionContext {
+    u8_file: Vec<u8>,
+    buffers: ConverterBuffers,
+    autoadd_cache: Option<AutoAddCache>,
+}
+
+impl ConversionContext {
+    /// Creates a context which reads auto-add files from disk for every conversion.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caches the auto-add files read by each conversion, so each file is only read from disk once
+    /// and is shared between conversions without being copied.
+    ///
+    /// Every conversion of a batch of tracks reads mostly the same auto-add files, however the cache lives
+    /// as long as the context and has no size limit, so grows to hold every auto-add file used by the converted
+    /// archives, at most the size of the library itself. Changes to cached files are not seen until [`Self::clear`]
+    /// is called, however files which are not part of the library are not cached, so files added to it are seen.
+    #[must_use]
+    pub fn with_autoadd_cache(mut self) -> Self {
+        self.autoadd_cache = Some(AutoAddCache::default());
+        self
+    }
+
+    /// Decompresses a WBZ file into the equivalent U8 file, returning the result.
+    ///
+    /// The result is stored in the context, so is overwritten by the next conversion.
+    ///
+    /// # Errors
+    /// See [`Self::decode_wbz_into`].
+    pub fn decode_wbz(
+        &mut self,
+        wbz_file: impl Read + Seek,
+        autoadd_path: Option<&Path>,
+    ) -> Result<&[u8], Error> {
+        let mut u8_file = std::mem::take(&mut self.u8_file);
+        let result = self.decode_wbz_into(wbz_file, autoadd_path, &mut u8_file);
+        self.u8_file = u8_file;
+
+        result.map(|()| self.u8_file.as_slice())
+    }
+
+    /// Decompresses a WBZ file into the equivalent U8 file, writing the result into `u8_file`.
+    ///
+    /// This is equivalent to [`decode_wbz_into`](crate::decode_wbz_into), but reuses the allocations of the
+    /// previous conversions.
+    ///
+    /// # Errors
+    /// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+    /// or if `autoadd_path` is provided but does not exist.
+    ///
+    /// See [`Error`] for all possible failure states.
+    pub fn decode_wbz_into(
+        &mut self,
+        wbz_file: impl Read + Seek,
+        autoadd_path: Option<&Path>,
+        u8_file: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        decompress_wbz_into(wbz_file, u8_file)?;
+
+        let autoadd = match (autoadd_path, &self.autoadd_cache) {
+            (Some(autoadd_path), _) if !autoadd_path.try_exists()? => {
+                return Err(Error::AutoAddNotFound(autoadd_path.to_owned()))
+            }
+            (Some(autoadd_path), Some(cache)) => Some(AutoAdd::Cached(autoadd_path, cache)),
+            (Some(autoadd_path), None) => Some(AutoAdd::Dir(autoadd_path)),
+            (None, _) => None,
+        };
+
+        let options = PassOptions {
+            autoadd,
+            ..PassOptions::new(None)
+        };
+
+        let mut converter = Converter::new(u8_file, false, options)?
+            .with_buffers(std::mem::take(&mut self.buffers));
+
+        let result = loop {
+            match converter.step() {
+                Ok(StepResult::Progress(_)) => {}
+                Ok(StepResult::Done) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+
+        self.buffers = converter.into_buffers();
+        result
+    }
+
+    /// Clears the auto-add cache, such as after the auto-add library has been updated.
+    pub fn clear(&mut self) {
+        if let Some(cache) = &mut self.autoadd_cache {
+            cache.get_mut().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{decode_wbz, encode_wbz, U8Builder};
+
+    #[test]
+    fn cache_is_reused_until_cleared() {
+        let dir =
+            std::env::temp_dir().join(format!("wbz_converter_{}_context", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("course.kmp"), b"auto-add kmp").unwrap();
+
+        let mut u8_file = U8Builder::new()
+            .add_file("./course.kmp", *b"track kmp")
+            .add_file("./course_model.brres", *b"brres")
+            .finish()
+            .unwrap();
+        let original = u8_file.clone();
+
+        let mut wbz_file = Vec::new();
+        encode_wbz(&mut u8_file, &mut wbz_file, Some(&dir)).unwrap();
+
+        let mut context = ConversionContext::new().with_autoadd_cache();
+        let mut decoded = Vec::new();
+        context
+            .decode_wbz_into(Cursor::new(&wbz_file), Some(&dir), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, original);
+
+        // The auto-add file is now only in the cache.
+        std::fs::remove_file(dir.join("course.kmp")).unwrap();
+        context
+            .decode_wbz_into(Cursor::new(&wbz_file), Some(&dir), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, original);
+
+        context.clear();
+        context
+            .decode_wbz_into(Cursor::new(&wbz_file), Some(&dir), &mut decoded)
+            .unwrap();
+        assert_eq!(
+            decoded,
+            decode_wbz(Cursor::new(&wbz_file), Some(&dir)).unwrap()
+        );
+        assert_ne!(decoded, original);
+
+        // Files missing from the library are not cached, so are seen once added.
+        std::fs::write(dir.join("course.kmp"), b"auto-add kmp").unwrap();
+        context
+            .decode_wbz_into(Cursor::new(&wbz_file), Some(&dir), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, original);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn context_reads_autoadd_without_cache() {
+        let dir = std::env::temp_dir().join(format!(
+            "wbz_converter_{}_context_uncached",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("course.kmp"), b"auto-add kmp").unwrap();
+
+        let mut u8_file = U8Builder::new()
+            .add_file("./course.kmp", *b"track kmp")
+            .add_file("./course_model.brres", *b"brres")
+            .finish()
+            .unwrap();
+        let original = u8_file.clone();
+
+        let mut wbz_file = Vec::new();
+        encode_wbz(&mut u8_file, &mut wbz_file, Some(&dir)).unwrap();
+
+        let mut context = ConversionContext::new();
+        assert_eq!(
+            context
+                .decode_wbz(Cursor::new(&wbz_file), Some(&dir))
+                .unwrap(),
+            original
+        );
+
+        std::fs::remove_file(dir.join("course.kmp")).unwrap();
+        assert_ne!(
+            context
+                .decode_wbz(Cursor::new(&wbz_file), Some(&dir))
+                .unwrap(),
+            original
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
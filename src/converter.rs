@@ -0,0 +1,528 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use crate::{
+    iterator::{AutoAdd, U8Entry, U8Iterator, U8Walker},
+    parser::{read_checked_header, U8Header},
+    passes::{
+        derive_starting_key, derived_key_contribution, find_overlapping_nodes, perform_header_pass,
+        perform_pass_one, perform_pass_two,
+    },
+    AutoAddMode, Error, PassOptions, Progress, U8Node, U8_MAGIC, WU8_MAGIC,
+};
+
+#[cfg(feature = "parallel")]
+use crate::passes::perform_pass_two_parallel;
+
+/// The result of a single [`Converter::step`].
+#[derive(Debug, Clone, Copy)]
+pub enum StepResult {
+    /// A file was processed, and there are more steps to run.
+    Progress(Progress),
+    /// The conversion has finished, and the file is fully converted.
+    Done,
+}
+
+/// A file which was not found in the auto-add library, waiting for pass two.
+struct PendingFile {
+    files_done: u32,
+    file: U8Entry,
+}
+
+/// Allocations made while converting a file, kept by [`ConversionContext`](crate::ConversionContext) for the next conversion.
+#[derive(Default)]
+pub(crate) struct ConverterBuffers {
+    /// A copy of the header, node table, and string table, which is walked while the file data is converted.
+    meta: Vec<u8>,
+    pending: Vec<PendingFile>,
+    /// Path strings which are no longer used, so can be reused for the next entries.
+    paths: Vec<String>,
+    #[cfg(feature = "parallel")]
+    pass_two_nodes: Vec<U8Node>,
+}
+
+impl ConverterBuffers {
+    /// Clears the buffers left by a conversion, keeping their allocations.
+    fn reset(&mut self) {
+        let pending = self
+            .pending
+            .drain(..)
+            .map(|pending| pending.file.into_path_string());
+        self.paths.extend(pending);
+        #[cfg(feature = "parallel")]
+        self.pass_two_nodes.clear();
+    }
+}
+
+/// A function called with the name, size, and running derived key of each file found in the auto-add library.
+pub(crate) type FileObserver<'a> = &'a mut dyn FnMut(&str, u64, u8);
+
+enum Pass {
+    /// The node table has not been read yet, see [`Converter::start`].
+    Start,
+    One(U8Walker),
+    /// The index of the next file in [`ConverterBuffers::pending`].
+    Two(usize),
+    Done,
+}
+
+/// Converts a WU8 or U8 file one file at a time, allowing the caller to stop between files.
+///
+/// The file is converted **in place**, so dropping the converter before [`StepResult::Done`]
+/// is returned leaves it partially converted.
+pub struct Converter<'a> {
+    file: &'a mut [u8],
+    encode: bool,
+    header: U8Header,
+    autoadd: Option<AutoAdd<'a>>,
+    autoadd_mode: AutoAddMode,
+    check_overlaps: bool,
+    missing_autoadd: Option<&'a mut Vec<PathBuf>>,
+    observer: Option<FileObserver<'a>>,
+    starting_key: u8,
+    header_pass: bool,
+    derived_key: u8,
+    files_total: u32,
+    bytes_done: u64,
+    pass: Pass,
+    buffers: ConverterBuffers,
+}
+
+impl<'a> Converter<'a> {
+    /// Parses a WU8 file to be decoded into the equivalent U8 file.
+    ///
+    /// # Errors
+    /// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+    /// or if `autoadd_path` is provided but does not exist.
+    ///
+    /// See [`Error`] for all possible failure states.
+    pub fn decode(wu8_file: &'a mut [u8], autoadd_path: Option<&'a Path>) -> Result<Self, Error> {
+        Self::new(wu8_file, false, PassOptions::new(autoadd_path))
+    }
+
+    /// Parses a U8 file to be encoded into the equivalent WU8 file.
+    ///
+    /// # Errors
+    /// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file,
+    /// or if `autoadd_path` is provided but does not exist.
+    ///
+    /// See [`Error`] for all possible failure states.
+    pub fn encode(u8_file: &'a mut [u8], autoadd_path: Option<&'a Path>) -> Result<Self, Error> {
+        Self::new(u8_file, true, PassOptions::new(autoadd_path))
+    }
+
+    pub(crate) fn new(
+        file: &'a mut [u8],
+        encode: bool,
+        options: PassOptions<'a>,
+    ) -> Result<Self, Error> {
+        if let Some(AutoAdd::Dir(autoadd_path)) = options.autoadd {
+            if !autoadd_path.try_exists()? {
+                return Err(Error::AutoAddNotFound(autoadd_path.to_owned()));
+            }
+        }
+
+        let size: u32 = file.len().try_into()?;
+        let starting_key = match options.starting_key {
+            Some(starting_key) => starting_key,
+            None => derive_starting_key(size),
+        };
+
+        debug!("Parsing header");
+        let header = read_checked_header(file, if encode { U8_MAGIC } else { WU8_MAGIC })?;
+        debug!(
+            "Parsed header node_offset={} meta_size={} data_offset={}",
+            header.node_offset, header.meta_size, header.data_offset
+        );
+
+        Ok(Self {
+            file,
+            encode,
+            header,
+            autoadd: options.autoadd,
+            autoadd_mode: options.autoadd_mode,
+            check_overlaps: options.check_overlaps,
+            missing_autoadd: options.missing_autoadd,
+            observer: options.observer,
+            starting_key,
+            header_pass: options.header_pass,
+            derived_key: starting_key,
+            files_total: 0,
+            bytes_done: 0,
+            pass: Pass::Start,
+            buffers: ConverterBuffers::default(),
+        })
+    }
+
+    /// Uses the allocations left by a previous conversion, see [`Self::into_buffers`].
+    pub(crate) fn with_buffers(mut self, mut buffers: ConverterBuffers) -> Self {
+        buffers.reset();
+        self.buffers = buffers;
+        self
+    }
+
+    /// Returns the allocations made by this conversion, even if it failed or did not finish.
+    pub(crate) fn into_buffers(mut self) -> ConverterBuffers {
+        self.buffers.reset();
+        self.buffers
+    }
+
+    /// Decodes the node and string tables and reads the root node, returning a walker over the node table.
+    ///
+    /// This is run by the first [`Self::step`], so creating a converter only reads the header.
+    fn start(&mut self) -> Result<U8Walker, Error> {
+        let header = self.header;
+        if !self.encode && self.header_pass {
+            // First pass, XOR all node and string table bytes with base key
+            perform_header_pass(
+                self.file,
+                self.starting_key,
+                header.node_offset,
+                header.meta_size,
+            );
+        }
+
+        // The metadata is copied so the walk cannot be changed by file data which overlaps it.
+        let meta = &mut self.buffers.meta;
+        meta.clear();
+        meta.extend_from_slice(&self.file[..header.meta_range().end]);
+
+        // Now, get the initial node to find the node table size
+        debug!("Calculating offsets for header data");
+        let walker = U8Walker::new(&self.buffers.meta, &header, self.file.len())?;
+        self.files_total = walker.node_count();
+
+        if self.check_overlaps {
+            debug!("Checking for overlapping file data");
+            let mut nodes_walker = walker.clone();
+            let nodes = std::iter::from_fn(|| nodes_walker.next_node(&self.buffers.meta))
+                .map(|next| next.map(|(_, node)| node))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if let Some((first, second)) = find_overlapping_nodes(&nodes) {
+                return Err(Error::OverlappingNodes { first, second });
+            }
+        }
+
+        self.autoadd = match self.autoadd_mode {
+            AutoAddMode::Always => self.autoadd,
+            AutoAddMode::Never => None,
+            AutoAddMode::Auto => match self.autoadd {
+                Some(autoadd) if autoadd.is_used(&self.buffers.meta, walker.clone())? => {
+                    Some(autoadd)
+                }
+                _ => None,
+            },
+        };
+
+        info!("Starting decode pass 1 (XOR all object files with auto-add library)");
+        Ok(walker)
+    }
+
+    /// Takes the walker for pass one, starting the conversion if needed.
+    ///
+    /// Returns `None` if pass one has already finished.
+    fn take_walker(&mut self) -> Result<Option<U8Walker>, Error> {
+        match std::mem::replace(&mut self.pass, Pass::Done) {
+            Pass::Start => self.start().map(Some),
+            Pass::One(walker) => Ok(Some(walker)),
+            Pass::Two(_) | Pass::Done => Ok(None),
+        }
+    }
+
+    /// Returns the key derived from the starting key and the auto-add library, see [`decode_wu8_with_key`](crate::decode_wu8_with_key).
+    ///
+    /// This is only the final key once pass two has started.
+    #[must_use]
+    pub fn derived_key(&self) -> u8 {
+        self.derived_key
+    }
+
+    /// Processes the next file, returning [`StepResult::Done`] once the conversion has finished.
+    ///
+    /// The first step decodes and walks the node table, so errors for an invalid node table are returned here
+    /// rather than when creating the converter.
+    ///
+    /// # Errors
+    /// Errors if the node table or file data is invalid, or the auto-add library could not be read.
+    ///
+    /// See [`Error`] for all possible failure states.
+    pub fn step(&mut self) -> Result<StepResult, Error> {
+        loop {
+            match &mut self.pass {
+                Pass::Start => {
+                    let walker = self.start()?;
+                    self.pass = Pass::One(walker);
+                }
+                Pass::One(walker) => {
+                    let buffers = &mut self.buffers;
+                    let Some(entry) = walker.next_entry_in(&buffers.meta, &mut buffers.paths)
+                    else {
+                        info!(
+                            "Starting pass 2 (XOR all non-object files with derived key) derived_key={}",
+                            self.derived_key
+                        );
+
+                        self.bytes_done = 0;
+                        self.pass = Pass::Two(0);
+                        continue;
+                    };
+
+                    if let Some(progress) = self.pass_one_file(entry?)? {
+                        return Ok(StepResult::Progress(progress));
+                    }
+                }
+                Pass::Two(index) => {
+                    let next = *index;
+                    if next == self.buffers.pending.len() {
+                        self.finish()?;
+                        return Ok(StepResult::Done);
+                    }
+
+                    *index += 1;
+                    return self.pass_two_file(next).map(StepResult::Progress);
+                }
+                Pass::Done => return Ok(StepResult::Done),
+            }
+        }
+    }
+
+    /// Decodes only the file at `path`, returning `None` if the archive does not contain it.
+    ///
+    /// Files in the auto-add library are returned as soon as they are found, however other files
+    /// depend on the derived key, so still require the auto-add library to be read for every file.
+    pub(crate) fn extract(mut self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let Some(mut walker) = self.take_walker()? else {
+            return Ok(None);
+        };
+
+        let path = Path::new(path);
+        let mut pending = None;
+        while let Some(file) = walker.next_entry(&self.buffers.meta) {
+            let file = file?;
+            if file.node.is_dir {
+                continue;
+            }
+
+            let original_data = match self.autoadd {
+                Some(autoadd) => autoadd.read(file.path())?,
+                None => None,
+            };
+
+            let is_target = file.path() == path;
+            match original_data {
+                Some(original_data) if is_target => {
+                    perform_pass_one(self.file, &original_data, file.node, self.starting_key)?;
+                    return Ok(Some(self.node_data(file.node)));
+                }
+                Some(original_data) => self.derived_key ^= derived_key_contribution(&original_data),
+                None if is_target => pending = Some(file.node),
+                None => {}
+            }
+        }
+
+        let Some(node) = pending else {
+            return Ok(None);
+        };
+
+        perform_pass_two(self.file, node, self.derived_key)?;
+        Ok(Some(self.node_data(node)))
+    }
+
+    /// Walks the archive, returning the path of every file which would be read from the auto-add library in pass one.
+    pub(crate) fn required_autoadd(mut self) -> Result<Vec<PathBuf>, Error> {
+        let Some(walker) = self.take_walker()? else {
+            return Ok(Vec::new());
+        };
+
+        let Some(autoadd) = self.autoadd else {
+            return Ok(Vec::new());
+        };
+
+        let mut required = Vec::new();
+        for file in U8Iterator::new(&self.buffers.meta, walker) {
+            let file = file?;
+            if !file.node.is_dir && autoadd.contains(file.path())? {
+                required.push(file.into_path());
+            }
+        }
+
+        Ok(required)
+    }
+
+    fn node_data(&self, node: U8Node) -> Vec<u8> {
+        let start = node.data_offset as usize;
+        self.file[start..start + node.size as usize].to_vec()
+    }
+
+    /// XORs `file` with the auto-add library, deferring it to pass two if it is not part of the library.
+    fn pass_one_file(&mut self, file: U8Entry) -> Result<Option<Progress>, Error> {
+        if file.node.is_dir {
+            self.buffers.paths.push(file.into_path_string());
+            return Ok(None);
+        }
+
+        let files_done = file.index + 1;
+
+        let original_data = match self.autoadd {
+            Some(autoadd) => autoadd.read(file.path())?,
+            None => None,
+        };
+
+        let Some(original_data) = original_data else {
+            self.buffers.pending.push(PendingFile { files_done, file });
+            return Ok(None);
+        };
+
+        let (node, name) = (file.node, file.name());
+
+        self.derived_key ^= derived_key_contribution(&original_data);
+
+        debug!(
+            "Starting auto-add XOR name={name:?} size={} derived_key={}",
+            node.size, self.derived_key
+        );
+        perform_pass_one(self.file, &original_data, node, self.starting_key)?;
+        if let Some(observer) = &mut self.observer {
+            observer(name, node.size.into(), self.derived_key);
+        }
+
+        self.bytes_done += node.size as u64;
+        self.buffers.paths.push(file.into_path_string());
+        Ok(Some(Progress {
+            pass: 1,
+            files_done,
+            files_total: self.files_total,
+            bytes_done: self.bytes_done,
+        }))
+    }
+
+    // Pass two is deferred to `finish` with the parallel feature, so only fails without it.
+    #[cfg_attr(feature = "parallel", allow(clippy::unnecessary_wraps))]
+    fn pass_two_file(&mut self, index: usize) -> Result<Progress, Error> {
+        let PendingFile { files_done, file } = &self.buffers.pending[index];
+        let (files_done, node) = (*files_done, file.node);
+
+        debug!("Starting XOR name={:?} size={}", file.name(), node.size);
+        #[cfg(feature = "parallel")]
+        self.buffers.pass_two_nodes.push(node);
+        #[cfg(not(feature = "parallel"))]
+        perform_pass_two(self.file, node, self.derived_key)?;
+
+        self.bytes_done += node.size as u64;
+        if let (Some(missing_autoadd), Some(_)) = (&mut self.missing_autoadd, self.autoadd) {
+            missing_autoadd.push(file.path().to_owned());
+        }
+
+        Ok(Progress {
+            pass: 2,
+            files_done,
+            files_total: self.files_total,
+            bytes_done: self.bytes_done,
+        })
+    }
+
+    // Pass two is performed here with the parallel feature, so only fails with it.
+    #[cfg_attr(not(feature = "parallel"), allow(clippy::unnecessary_wraps))]
+    fn finish(&mut self) -> Result<(), Error> {
+        self.pass = Pass::Done;
+
+        #[cfg(feature = "parallel")]
+        perform_pass_two_parallel(
+            self.file,
+            &mut self.buffers.pass_two_nodes,
+            self.derived_key,
+        )?;
+
+        let header = self.header;
+        if self.encode && self.header_pass {
+            // Last pass, XOR all node and string table bytes with base key
+            perform_header_pass(
+                self.file,
+                self.starting_key,
+                header.node_offset,
+                header.meta_size,
+            );
+        }
+
+        // Setup new magic
+        let magic = if self.encode { WU8_MAGIC } else { U8_MAGIC };
+        self.file[0..4].copy_from_slice(&header.endianness.magic(magic));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_wu8, U8Builder};
+
+    fn sample_archive() -> Vec<u8> {
+        U8Builder::new()
+            .add_file("./course.kmp", *b"kmp")
+            .add_file("./course_model.brres", *b"brres")
+            .finish()
+            .unwrap()
+    }
+
+    #[test]
+    fn new_only_reads_header() {
+        let mut u8_file = sample_archive();
+
+        // Give the last node an invalid type, which is only found once the node table is walked.
+        u8_file[0x20 + 3 * 12] = 5;
+
+        let mut converter = Converter::encode(&mut u8_file, None).unwrap();
+        assert!(matches!(
+            converter.step(),
+            Err(Error::AtNode { index: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn header_pass_runs_in_first_step() {
+        let u8_file = sample_archive();
+        let mut wu8_file = u8_file.clone();
+        encode_wu8(&mut wu8_file, None).unwrap();
+        let encoded = wu8_file.clone();
+
+        let mut converter = Converter::decode(&mut wu8_file, None).unwrap();
+        assert_eq!(converter.file, &encoded[..]);
+
+        let mut steps = 0;
+        while let StepResult::Progress(progress) = converter.step().unwrap() {
+            assert_eq!(progress.files_total, 4);
+            steps += 1;
+        }
+
+        assert_eq!(steps, 2);
+        assert_eq!(wu8_file, u8_file);
+    }
+
+    #[test]
+    fn buffers_are_reused() {
+        let u8_file = sample_archive();
+        let mut wu8_file = u8_file.clone();
+        encode_wu8(&mut wu8_file, None).unwrap();
+
+        let mut buffers = ConverterBuffers::default();
+        for _ in 0..2 {
+            let mut decoded = wu8_file.clone();
+            let mut converter = Converter::decode(&mut decoded, None)
+                .unwrap()
+                .with_buffers(buffers);
+
+            while let StepResult::Progress(_) = converter.step().unwrap() {}
+            buffers = converter.into_buffers();
+
+            assert_eq!(decoded, u8_file);
+            assert_eq!(buffers.meta[4..], u8_file[4..buffers.meta.len()]);
+            assert!(buffers.pending.is_empty());
+
+            // Directory paths are reused for the next entry, so only the pending files need their own path.
+            assert_eq!(buffers.paths.len(), 2);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+/// The reflected polynomial of the CRC-32 used by zlib, PNG, and most other formats.
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+const TABLE: [u32; 256] = build_table();
+
+const fn build_table() -> [u32; 256] {
+    let mut table = [0; 256];
+
+    let mut i: u32 = 0;
+    while i < 256 {
+        let mut value = i;
+
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 == 1 {
+                (value >> 1) ^ POLYNOMIAL
+            } else {
+                value >> 1
+            };
+
+            bit += 1;
+        }
+
+        table[i as usize] = value;
+        i += 1;
+    }
+
+    table
+}
+
+/// Computes the CRC-32 checksum of `data`.
+pub(crate) fn checksum(data: &[u8]) -> u32 {
+    let crc = data.iter().fold(u32::MAX, |crc, &byte| {
+        TABLE[usize::from(crc.to_le_bytes()[0] ^ byte)] ^ (crc >> 8)
+    });
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(checksum(b""), 0);
+    }
+}
@@ -1,120 +1,582 @@
 use std::{
     cell::RefCell,
+    collections::BTreeMap,
     io::Cursor,
+    ops::{Deref, Range},
     path::{Path, PathBuf},
-    rc::Rc,
+    sync::Arc,
 };
 
-use arrayvec::ArrayVec;
 use log::debug;
 
-use crate::{parser::Parser, Error, U8Node};
+use crate::{
+    parser::{Endianness, Parser, U8Header},
+    Error, U8Node,
+};
 
-pub(crate) enum U8NodeItem {
-    File {
-        node: U8Node,
-        original_data: Option<Vec<u8>>,
-        name: String,
-    },
-    Error(Error),
-    Directory,
+/// A node found while walking the node table, with its full path in the archive.
+pub(crate) struct U8Entry {
+    pub index: u32,
+    pub node: U8Node,
+    path: String,
+    name_start: usize,
 }
 
-#[allow(clippy::module_name_repetitions)]
-pub(crate) struct U8Iterator<'a, 'b> {
-    file: Rc<RefCell<Parser<Cursor<&'b mut [u8]>>>>,
-    dir_stack: ArrayVec<U8Node, 5>,
-    string_table_start: u32,
-    autoadd_path: &'a Path,
-    node_count: u32,
-    iteration: u32,
-}
-
-impl<'a, 'b> U8Iterator<'a, 'b> {
-    pub fn new(
-        file: Rc<RefCell<Parser<Cursor<&'b mut [u8]>>>>,
-        nodes: u32,
-        string_table_start: u32,
-        autoadd_path: &'a Path,
-    ) -> Self {
-        Self {
-            file,
-            autoadd_path,
-            iteration: 0,
-            node_count: nodes,
-            string_table_start,
-            dir_stack: ArrayVec::new(),
-        }
+impl U8Entry {
+    pub fn name(&self) -> &str {
+        &self.path[self.name_start..]
+    }
+
+    pub fn path(&self) -> &Path {
+        Path::new(&self.path)
+    }
+
+    pub fn path_str(&self) -> &str {
+        &self.path
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        PathBuf::from(self.path)
+    }
+
+    pub fn into_path_string(self) -> String {
+        self.path
     }
 }
 
-impl Iterator for U8Iterator<'_, '_> {
-    type Item = U8NodeItem;
+/// A directory which is currently being walked.
+#[derive(Clone)]
+struct OpenDir {
+    /// The index after the directory's last child.
+    end: u32,
+    /// The length of the walker's `dir_path` before this directory was appended.
+    parent_path_len: usize,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.iteration == self.node_count {
+/// Walks the node table of a U8 file, tracking the path of each node.
+///
+/// The walker does not borrow the file, so the caller can modify the file data between nodes,
+/// however the node and string tables passed to each call must stay the same.
+#[derive(Clone)]
+pub(crate) struct U8Walker {
+    endianness: Endianness,
+    node_offset: u32,
+    string_table: Range<u32>,
+    node_count: u32,
+    /// The length of the full file, which file data is checked to lie within.
+    file_len: usize,
+    index: u32,
+    dir_stack: Vec<OpenDir>,
+    /// The path of the innermost open directory, with a trailing `/` after each directory name.
+    dir_path: String,
+}
+
+impl U8Walker {
+    /// Reads the root node of a U8 file whose header was read by [`read_checked_header`].
+    ///
+    /// `meta` must contain the node and string tables at the offsets given by `header`,
+    /// and `file_len` is the length of the full file.
+    pub fn new(meta: &[u8], header: &U8Header, file_len: usize) -> Result<Self, Error> {
+        let mut reader = Parser::with_endianness(Cursor::new(meta), header.endianness);
+        reader.set_position(header.node_offset)?;
+        let root_node = reader.read_root_node()?;
+
+        let string_table_start = header.node_offset + header.node_table_size(root_node.size)?;
+        debug!(
+            "Calculated offsets node_count={} string_table_start={string_table_start}",
+            root_node.size
+        );
+
+        Ok(Self {
+            endianness: header.endianness,
+            node_offset: header.node_offset,
+            string_table: string_table_start..header.node_offset + header.meta_size,
+            node_count: root_node.size,
+            file_len,
+            index: 0,
+            dir_stack: Vec::new(),
+            dir_path: String::new(),
+        })
+    }
+
+    /// Returns the number of nodes in the archive, including the root.
+    pub fn node_count(&self) -> u32 {
+        self.node_count
+    }
+
+    /// Reads the next node without its name.
+    ///
+    /// If the node cannot be read, the rest of the node table cannot be read either, so the walk is finished.
+    pub fn next_node(&mut self, meta: &[u8]) -> Option<Result<(u32, U8Node), Error>> {
+        if self.index >= self.node_count {
             return None;
         }
 
-        self.iteration += 1;
+        let index = self.index;
+        let mut reader = Parser::with_endianness(Cursor::new(meta), self.endianness);
 
-        let mut file = self.file.borrow_mut();
-        let node = match file.read_node() {
-            Ok(node) => node,
-            Err(err) => return Some(U8NodeItem::Error(err)),
-        };
+        // The node table was checked to fit within the metadata when creating the walker, so this cannot overflow.
+        let node = reader
+            .set_position(self.node_offset + index * 12)
+            .map_err(Error::from)
+            .and_then(|()| reader.read_node());
 
-        let name_offset: u32 = node.name_offset.into();
-        let name = match file.read_string(self.string_table_start, name_offset) {
-            Ok(name) => name,
-            Err(err) => return Some(U8NodeItem::Error(err)),
-        };
+        match node {
+            Ok(node) => {
+                self.index += 1;
+                Some(Ok((index, node)))
+            }
+            Err(err) => {
+                self.index = self.node_count;
+                Some(Err(at_node(index, None, err)))
+            }
+        }
+    }
 
-        while let Some(current_dir) = self.dir_stack.last() {
-            if current_dir.size == self.iteration - 1 {
-                let dir_off = current_dir.name_offset.into();
+    /// Reads the next node and its full path.
+    ///
+    /// An invalid node is returned as an error without finishing the walk, so the caller can decide whether
+    /// to skip it. If a directory's name is invalid, its children are skipped.
+    pub fn next_entry(&mut self, meta: &[u8]) -> Option<Result<U8Entry, Error>> {
+        self.next_entry_in(meta, &mut Vec::new())
+    }
 
-                let dir_res = file.read_string(self.string_table_start, dir_off);
-                let dir_name = dir_res.as_deref().unwrap_or("No name!");
+    /// Reads the next node and its full path, building the path in a string taken from `paths` if there are any.
+    pub fn next_entry_in(
+        &mut self,
+        meta: &[u8],
+        paths: &mut Vec<String>,
+    ) -> Option<Result<U8Entry, Error>> {
+        let (index, node) = match self.next_node(meta)? {
+            Ok(next) => next,
+            Err(err) => return Some(Err(err)),
+        };
 
-                debug!("Found the end of {dir_name}");
+        while let Some(current_dir) = self.dir_stack.last() {
+            if current_dir.end <= index {
+                debug!(
+                    "Found the end of {}",
+                    &self.dir_path[current_dir.parent_path_len..]
+                );
+                self.dir_path.truncate(current_dir.parent_path_len);
                 self.dir_stack.pop();
             } else {
                 break;
             }
         }
 
-        if node.is_dir {
+        let reader = Parser::with_endianness(Cursor::new(meta), self.endianness);
+        let name = match reader.read_str(self.string_table.clone(), node.name_offset.into()) {
+            Ok(name) => name,
+            Err(err) => {
+                if node.is_dir {
+                    self.skip_to(node.size);
+                }
+
+                return Some(Err(at_node(index, None, err)));
+            }
+        };
+
+        // The root directory is always the first node, and its name is not part of the path.
+        let name = if index == 0 { "" } else { name };
+
+        if let Err(err) = node.check_dir_size(index, self.node_count) {
+            // The children cannot be found, so are treated as part of the parent directory.
+            return Some(Err(at_node(index, Some(name), err)));
+        }
+
+        if !node.is_dir {
+            let data_end = u64::from(node.data_offset) + u64::from(node.size);
+            if data_end > self.file_len as u64 {
+                let err = Error::NodeOutOfBounds {
+                    data_offset: node.data_offset,
+                    size: node.size,
+                };
+
+                return Some(Err(at_node(index, Some(name), err)));
+            }
+        }
+
+        let name_start = self.dir_path.len();
+        let mut path = paths.pop().unwrap_or_default();
+        path.clear();
+        path.push_str(&self.dir_path);
+        path.push_str(name);
+
+        if node.is_dir && index != 0 {
             debug!("Entering directory {name}");
-            self.dir_stack.push(node);
+            self.dir_stack.push(OpenDir {
+                end: node.size,
+                parent_path_len: name_start,
+            });
+
+            self.dir_path.push_str(name);
+            self.dir_path.push('/');
+        }
+
+        Some(Ok(U8Entry {
+            index,
+            node,
+            path,
+            name_start,
+        }))
+    }
+
+    /// Skips every node before `end`, such as the children of a directory.
+    fn skip_to(&mut self, end: u32) {
+        if end > self.index {
+            self.index = end.min(self.node_count);
+        }
+    }
+}
+
+fn at_node(index: u32, name: Option<&str>, err: Error) -> Error {
+    Error::AtNode {
+        index,
+        name: name.map(str::to_owned),
+        source: Box::new(err),
+    }
+}
+
+/// Walks the node table, yielding every node including the root.
+///
+/// This only reads the node and string tables, the auto-add library is read separately by [`AutoAdd`].
+#[allow(clippy::module_name_repetitions)]
+pub(crate) struct U8Iterator<'a> {
+    meta: &'a [u8],
+    walker: U8Walker,
+}
+
+impl<'a> U8Iterator<'a> {
+    pub fn new(meta: &'a [u8], walker: U8Walker) -> Self {
+        Self { meta, walker }
+    }
+}
+
+impl Iterator for U8Iterator<'_> {
+    type Item = Result<U8Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walker.next_entry(self.meta)
+    }
+}
+
+/// Reads the file at `path` from an auto-add library directory, returning `None` if it does not exist.
+pub(crate) fn read_autoadd_file(dir: &Path, path: &Path) -> std::io::Result<Option<Vec<u8>>> {
+    match std::fs::read(dir.join(path)) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Appends the `/` separated path relative to the library root and size of every file under `dir`.
+#[cfg(feature = "sha256")]
+pub(crate) fn list_autoadd_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<(String, u64)>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = format!("{prefix}{}", entry.file_name().to_string_lossy());
+
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_autoadd_files(&entry.path(), &format!("{path}/"), files)?;
+        } else {
+            files.push((path, metadata.len()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Empty auto-add files cannot be used as a key, so are treated as missing.
+fn non_empty(data: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    data.filter(|data| !data.is_empty())
+}
+
+/// A function which reads a file from the auto-add library, returning `None` if it is not part of the library.
+pub(crate) type AutoAddResolver<'a> = &'a dyn Fn(&Path) -> std::io::Result<Option<Vec<u8>>>;
+
+/// Files read from an auto-add library directory, keyed by their full path, see [`ConversionContext`](crate::ConversionContext).
+///
+/// Only files which are part of the library are cached, so missing files are looked up again every time.
+pub(crate) type AutoAddCache = RefCell<BTreeMap<PathBuf, Arc<[u8]>>>;
+
+/// The contents of a file from the auto-add library.
+pub(crate) enum AutoAddData {
+    Owned(Vec<u8>),
+    Shared(Arc<[u8]>),
+}
+
+impl Deref for AutoAddData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(data) => data,
+            Self::Shared(data) => data,
+        }
+    }
+}
+
+/// Where files from the auto-add library are read from.
+#[derive(Clone, Copy)]
+pub(crate) enum AutoAdd<'a> {
+    /// A directory containing the extracted auto-add library.
+    Dir(&'a Path),
+    /// A directory containing the extracted auto-add library, with each file only read once.
+    Cached(&'a Path, &'a AutoAddCache),
+    /// A caller provided function, such as for a packed or remote library.
+    Resolver(AutoAddResolver<'a>),
+}
+
+impl AutoAdd<'_> {
+    /// Reads the file at `path` from the auto-add library, returning `None` if it is not part of the library.
+    pub fn read(self, path: &Path) -> Result<Option<AutoAddData>, Error> {
+        let (dir, cache) = match self {
+            Self::Dir(dir) => {
+                return Ok(non_empty(read_autoadd_file(dir, path)?).map(AutoAddData::Owned))
+            }
+            Self::Resolver(resolver) => {
+                return Ok(non_empty(resolver(path)?).map(AutoAddData::Owned))
+            }
+            Self::Cached(dir, cache) => (dir, cache),
+        };
 
-            return Some(U8NodeItem::Directory);
+        let full_path = dir.join(path);
+        if let Some(data) = cache.borrow().get(&full_path) {
+            return Ok(Some(AutoAddData::Shared(data.clone())));
         }
 
-        let dir_iter = self
-            .dir_stack
-            .iter()
-            .map(|node| file.read_string(self.string_table_start, node.name_offset.into()));
-
-        let path = match std::iter::once(Ok(self.autoadd_path.to_string_lossy().into_owned()))
-            .chain(dir_iter)
-            .chain(std::iter::once(Ok(name.clone())))
-            .collect::<Result<PathBuf, Error>>()
-        {
-            Ok(path) => path,
-            Err(err) => return Some(U8NodeItem::Error(err)),
+        let Some(data) = non_empty(read_autoadd_file(dir, path)?) else {
+            return Ok(None);
         };
 
-        let original_data = match std::fs::read(path) {
-            Ok(data) => Some(data),
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
-            Err(err) => return Some(U8NodeItem::Error(Error::FileOperationFailed(err))),
+        let data: Arc<[u8]> = data.into();
+        cache.borrow_mut().insert(full_path, data.clone());
+        Ok(Some(AutoAddData::Shared(data)))
+    }
+
+    /// Checks if the file at `path` is part of the auto-add library, without reading it if possible.
+    pub fn contains(self, path: &Path) -> Result<bool, Error> {
+        let dir = match self {
+            Self::Dir(dir) => dir,
+            Self::Cached(dir, cache) if !cache.borrow().contains_key(&dir.join(path)) => dir,
+            Self::Cached(..) => return Ok(true),
+            Self::Resolver(_) => return Ok(self.read(path)?.is_some()),
         };
 
-        Some(U8NodeItem::File {
-            node,
+        // Empty auto-add files cannot be used as a key, so are treated as missing.
+        match std::fs::metadata(dir.join(path)) {
+            Ok(metadata) => Ok(metadata.is_file() && metadata.len() != 0),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Walks the archive until a file is found in the auto-add library.
+    pub fn is_used(self, meta: &[u8], walker: U8Walker) -> Result<bool, Error> {
+        for entry in U8Iterator::new(meta, walker) {
+            let entry = entry?;
+            if !entry.node.is_dir && self.contains(entry.path())? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decode_wu8, encode_wu8, parser::read_checked_header, U8Archive, U8Builder, U8_MAGIC,
+    };
+
+    fn walker(u8_file: &[u8]) -> U8Walker {
+        let header = read_checked_header(u8_file, U8_MAGIC).unwrap();
+        U8Walker::new(u8_file, &header, u8_file.len()).unwrap()
+    }
+
+    fn walk(u8_file: &[u8]) -> Vec<Result<U8Entry, Error>> {
+        U8Iterator::new(u8_file, walker(u8_file)).collect()
+    }
+
+    /// Creates an empty directory which is unique to this test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wbz_converter_{}_{name}", std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn set_node_size(u8_file: &mut [u8], index: usize, size: u32) {
+        let offset = 0x20 + index * 12 + 8;
+        u8_file[offset..offset + 4].copy_from_slice(&size.to_be_bytes());
+    }
+
+    fn paths(u8_file: &[u8]) -> Vec<String> {
+        walk(u8_file)
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path_string())
+            .collect()
+    }
+
+    #[test]
+    fn root_is_detected_by_index() {
+        let u8_file = U8Builder::new()
+            .add_file("course.kmp", *b"kmp")
+            .finish()
+            .unwrap();
+
+        // The file's name directly follows the root's empty name.
+        let type_and_name = &u8_file[0x20 + 12..0x20 + 16];
+        assert_eq!(type_and_name, [0, 0, 0, 1]);
+
+        let entries = walk(&u8_file);
+        let file = entries[1].as_ref().unwrap();
+        assert!(!file.node.is_dir);
+        assert_eq!(file.path_str(), "course.kmp");
+        assert_eq!(file.name(), "course.kmp");
+    }
+
+    #[test]
+    fn deeply_nested_directories() {
+        let u8_file = U8Builder::new()
+            .add_file("./a/b/c/d/e/f/deep.bin", *b"deep")
+            .add_file("./a/b/c/d/e/f/g/deeper.bin", *b"deeper")
+            .add_file("./a/b/c/d/e/shallower.bin", *b"shallower")
+            .add_file("./a/top.bin", *b"top")
+            .add_file("./course.kmp", *b"kmp")
+            .finish()
+            .unwrap();
+
+        assert_eq!(
+            paths(&u8_file),
+            [
+                "",
+                ".",
+                "./a",
+                "./a/b",
+                "./a/b/c",
+                "./a/b/c/d",
+                "./a/b/c/d/e",
+                "./a/b/c/d/e/f",
+                "./a/b/c/d/e/f/deep.bin",
+                "./a/b/c/d/e/f/g",
+                "./a/b/c/d/e/f/g/deeper.bin",
+                "./a/b/c/d/e/shallower.bin",
+                "./a/top.bin",
+                "./course.kmp",
+            ]
+        );
+    }
+
+    #[test]
+    fn error_includes_name_once_read() {
+        let mut u8_file = U8Builder::new()
+            .add_file("./posteffect/posteffect.bfg", *b"bfg")
+            .finish()
+            .unwrap();
+
+        set_node_size(&mut u8_file, 2, 10);
+
+        let Err(Error::AtNode {
+            index,
             name,
-            original_data,
-        })
+            source,
+        }) = U8Archive::new(&u8_file)
+        else {
+            panic!("invalid directory size was accepted");
+        };
+
+        assert_eq!(index, 2);
+        assert_eq!(name.as_deref(), Some("posteffect"));
+        assert!(matches!(*source, Error::InvalidDirectorySize { .. }));
+    }
+
+    #[test]
+    fn error_without_name_if_unreadable() {
+        let mut u8_file = U8Builder::new()
+            .add_file("./course.kmp", *b"kmp")
+            .finish()
+            .unwrap();
+
+        // Point the file's name past the end of the string table.
+        u8_file[0x20 + 2 * 12 + 3] = 0xFF;
+
+        let entries = walk(&u8_file);
+        assert!(matches!(
+            entries[2],
+            Err(Error::AtNode {
+                index: 2,
+                name: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn empty_autoadd_file_is_missing() {
+        let dir = temp_dir("empty_autoadd");
+        std::fs::write(dir.join("course.kmp"), b"").unwrap();
+
+        let path = Path::new("./course.kmp");
+        let cache = AutoAddCache::default();
+        let resolver = |_: &Path| Ok(Some(Vec::new()));
+        for autoadd in [
+            AutoAdd::Dir(&dir),
+            AutoAdd::Cached(&dir, &cache),
+            AutoAdd::Resolver(&resolver),
+        ] {
+            assert!(autoadd.read(path).unwrap().is_none());
+        }
+
+        // The file is XORed with the derived key instead, the same as without a library.
+        let u8_file = U8Builder::new()
+            .add_file("./course.kmp", *b"kmp")
+            .finish()
+            .unwrap();
+
+        let mut wu8_file = u8_file.clone();
+        encode_wu8(&mut wu8_file, Some(&dir)).unwrap();
+
+        let mut expected = u8_file.clone();
+        encode_wu8(&mut expected, None).unwrap();
+        assert_eq!(wu8_file, expected);
+
+        decode_wu8(&mut wu8_file, Some(&dir)).unwrap();
+        assert_eq!(wu8_file, u8_file);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn is_used_checks_existence() {
+        let dir = temp_dir("is_used");
+        std::fs::create_dir(dir.join("posteffect")).unwrap();
+        std::fs::write(dir.join("course.kmp"), b"").unwrap();
+
+        let u8_file = U8Builder::new()
+            .add_file("./course.kmp", *b"kmp")
+            .add_file("./posteffect/posteffect.bfg", *b"bfg")
+            .finish()
+            .unwrap();
+
+        // Empty files and directories are not part of the library.
+        let autoadd = AutoAdd::Dir(&dir);
+        assert!(!autoadd.contains(Path::new("./course.kmp")).unwrap());
+        assert!(!autoadd.contains(Path::new("./posteffect")).unwrap());
+        assert!(!autoadd.is_used(&u8_file, walker(&u8_file)).unwrap());
+
+        std::fs::write(dir.join("posteffect/posteffect.bfg"), b"bfg").unwrap();
+        assert!(autoadd.is_used(&u8_file, walker(&u8_file)).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -3,32 +3,117 @@
 //! This library allows you to convert [WBZ](https://wiki.tockdom.com/wiki/WBZ) files and [WU8](https://wiki.tockdom.com/wiki/WU8_(File_Format))
 //! files into [U8](https://wiki.tockdom.com/wiki/U8_(File_Format)) files, and back, for use in Mario Kart Wii modding.
 //!
+//! Conversions are deterministic, so the same input and auto-add library always produce byte identical output.
+//! Files are processed in node table order, and the derived key combines auto-add files with XOR so does not
+//! depend on the order they are read in. Any caches, such as in [`ConversionContext`], use ordered maps.
+//!
 //! This library has not been fully tested, so here be dragons.
 
 #![warn(clippy::pedantic)]
 #![allow(clippy::cast_lossless, clippy::similar_names)]
 
 use std::{
-    cell::RefCell,
-    io::{Cursor, ErrorKind, Read, Seek, Write},
-    path::Path,
-    rc::Rc,
+    io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
-use log::{debug, info};
+use log::debug;
 
 use crate::{
-    iterator::{U8Iterator, U8NodeItem},
-    parser::Parser,
-    passes::{derive_starting_key, perform_header_pass, perform_pass_one, perform_pass_two},
+    converter::FileObserver, iterator::AutoAdd, parser::Parser, passes::derive_starting_key,
 };
 
+mod archive;
+mod builder;
+mod context;
+mod converter;
+mod crc32;
 mod iterator;
+mod options;
 mod parser;
 mod passes;
+#[cfg(feature = "sha256")]
+mod sha256;
+mod yaz0;
+
+pub use archive::{
+    format_tree, iter_u8, list_u8, list_wu8, node_info, scan_u8, BrresExtractor, Dir, Entry,
+    EntryInfo, EntryIter, NodeInfo, NodeRecord, NodeScan, U8Archive,
+};
+pub use builder::U8Builder;
+pub use context::ConversionContext;
+pub use converter::{Converter, StepResult};
+pub use options::ConversionOptions;
+pub use parser::{Endianness, U8Header};
+pub use yaz0::Yaz0Compression;
 
 const U8_MAGIC: [u8; 4] = [0x55, 0xAA, 0x38, 0x2D];
 const WU8_MAGIC: [u8; 4] = *b"WU8a";
+const WBZ_MAGIC: [u8; 8] = *b"WBZaWU8a";
+
+/// The node offset of every WU8 file produced by Wiimm's SZS Tools, directly after the 32 byte header.
+const WBZ_NODE_OFFSET: u32 = 0x20;
+
+/// The number of bytes either side of a difference included in a [`Mismatch`].
+const MISMATCH_CONTEXT: usize = 16;
+
+/// The size of the smallest file which is too big for the 32 bit offsets in a U8 file.
+const MAX_FILE_SIZE: u64 = 1 << 32;
+
+/// The number of decompressed bytes passed to each call of `on_chunk`, see [`decode_wbz_with_chunks`].
+const DECOMPRESS_CHUNK_SIZE: u64 = 0x10000;
+
+/// The largest compression ratio to reserve memory for up front, as a compressed header can claim any size.
+///
+/// Files which compress better than this are still decompressed, the buffer just grows as they are read.
+const MAX_RESERVE_RATIO: u64 = 16;
+
+/// The header of a WBZ file, following the `WBZaWU8a` magic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WbzHeader {
+    /// The node offset of the compressed WU8 file, copied from its header.
+    pub node_offset: u32,
+    /// The size of the compressed WU8 file once decompressed.
+    pub wu8_size: u32,
+}
+
+/// The level of bzip2 compression used when encoding a WBZ file.
+///
+/// Lower levels use a smaller block size, so they encode faster but produce larger files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WbzCompression(u32);
+
+impl WbzCompression {
+    /// Creates a new compression spec with a specific numeric level (1-9).
+    #[must_use]
+    pub fn new(level: u32) -> Self {
+        Self(level.clamp(1, 9))
+    }
+
+    /// Optimize for the best speed of encoding.
+    #[must_use]
+    pub fn fast() -> Self {
+        Self(1)
+    }
+
+    /// Optimize for the size of data being encoded.
+    #[must_use]
+    pub fn best() -> Self {
+        Self(9)
+    }
+
+    /// Returns the compression level as an integer.
+    #[must_use]
+    pub fn level(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for WbzCompression {
+    fn default() -> Self {
+        Self::best()
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct U8Node {
@@ -38,6 +123,143 @@ pub(crate) struct U8Node {
     size: u32,
 }
 
+impl U8Node {
+    /// Checks a directory at `index` ends after itself and within a node table of `node_count` nodes.
+    ///
+    /// A directory's size is the index one past its last child, so anything else would leave it open forever.
+    fn check_dir_size(&self, index: u32, node_count: u32) -> Result<(), Error> {
+        if self.is_dir && (self.size <= index || self.size > node_count) {
+            return Err(Error::InvalidDirectorySize {
+                size: self.size,
+                node_count,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Controls whether the first pass, which XORs files with the auto-add library, is performed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AutoAddMode {
+    /// Always look up every file in the auto-add library.
+    #[default]
+    Always,
+    /// Never look up files in the auto-add library, skipping the first pass entirely.
+    Never,
+    /// Probe the auto-add library for any matching files first, skipping the first pass if none are found.
+    Auto,
+}
+
+/// The file formats which can be converted by this library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A plain U8 archive.
+    U8,
+    /// A U8 archive encoded with the auto-add library and XOR keys.
+    Wu8,
+    /// A WU8 archive compressed with bzip2.
+    Wbz,
+}
+
+impl Format {
+    /// Every format, in the order they are checked by [`Format::from_magic`].
+    const ALL: [Self; 3] = [Self::Wbz, Self::Wu8, Self::U8];
+
+    /// Returns the magic bytes at the start of every file of this format.
+    #[must_use]
+    pub fn magic(self) -> &'static [u8] {
+        match self {
+            Self::U8 => &U8_MAGIC,
+            Self::Wu8 => &WU8_MAGIC,
+            Self::Wbz => &WBZ_MAGIC,
+        }
+    }
+
+    /// Returns the usual file extension of this format, without the leading `.`.
+    #[must_use]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::U8 => "u8",
+            Self::Wu8 => "wu8",
+            Self::Wbz => "wbz",
+        }
+    }
+
+    /// Detects the format of a file starting with `bytes` by its magic, returning `None` if the format is not recognised.
+    ///
+    /// U8 and WU8 files are also detected in little-endian byte order, see [`Endianness`].
+    #[must_use]
+    pub fn from_magic(bytes: &[u8]) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| {
+            let magic = format.magic();
+            let little_endian = *format != Self::Wbz
+                && bytes
+                    .get(..magic.len())
+                    .is_some_and(|start| start.iter().eq(magic.iter().rev()));
+
+            bytes.starts_with(magic) || little_endian
+        })
+    }
+}
+
+/// The location of each section of a U8 or WU8 file, see [`archive_layout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveLayout {
+    /// The number of nodes in the node table, including the root.
+    pub node_count: u32,
+    /// The offset of the node table.
+    pub node_offset: u32,
+    /// The size of the node table in bytes.
+    pub node_table_size: u32,
+    /// The offset of the string table, directly after the node table.
+    pub string_table_start: u32,
+    /// The offset of the end of the string table.
+    pub string_table_end: u32,
+    /// The offset of the first file's data.
+    pub data_offset: u32,
+}
+
+/// The first difference between two files, see [`assert_wu8_matches`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Files first differ at offset {offset:#x}")]
+pub struct Mismatch {
+    /// The offset of the first differing byte, or the length of the shorter file if one is a prefix of the other.
+    pub offset: usize,
+    /// The offset of the first byte in `ours` and `reference`.
+    pub context_start: usize,
+    /// The bytes of our file surrounding the difference.
+    pub ours: Vec<u8>,
+    /// The bytes of the reference file surrounding the difference.
+    pub reference: Vec<u8>,
+}
+
+/// A failed conversion, alongside the partially converted file, see [`decode_wbz_partial`].
+#[derive(thiserror::Error, Debug)]
+#[error("Conversion failed after {processed_nodes} files were processed")]
+pub struct PartialError {
+    /// The number of files which were processed before the conversion failed.
+    pub processed_nodes: u32,
+    /// The partially converted file, which may be empty or truncated if decompression failed.
+    pub buffer: Vec<u8>,
+    /// The error which stopped the conversion.
+    #[source]
+    pub source: Error,
+}
+
+/// The progress of a conversion, reported once per file processed by one of the XOR passes.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// The pass currently running, `1` for auto-add files and `2` for all other files.
+    pub pass: u8,
+    /// The number of nodes walked so far in this pass, including directories.
+    pub files_done: u32,
+    /// The total number of nodes in the archive, including directories.
+    pub files_total: u32,
+    /// The number of file bytes processed so far in this pass.
+    pub bytes_done: u64,
+}
+
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 #[rustfmt::skip]
@@ -46,215 +268,1707 @@ pub enum Error {
     BZip(bzip2::Error),
     #[error("The file provided is above 4GB in size")]
     FileTooBig(#[from] #[source] std::num::TryFromIntError),
+    #[error("The file provided is too small to contain its magic")]
+    FileTooSmall,
     #[error("Underlying error when reading from file")]
     FileOperationFailed(#[from] #[source] std::io::Error),
     #[error("WBZ file did not contain valid magic")]
     InvalidWBZMagic { found_magic: [u8; 8] },
+    #[error("WBZ file contained an unsupported header")]
+    UnsupportedWbzHeader(WbzHeader),
+    #[error("WBZ file decompressed to a different size than its header stated")]
+    WbzSizeMismatch { expected: u32, found: u64 },
+    #[error("WBZ file decompressed to a WU8 file with an unexpected CRC-32 checksum")]
+    ChecksumMismatch { expected: u32, found: u32 },
+    #[error("Converted file did not reproduce the original file, first differing at offset {offset:#x}")]
+    RoundTripMismatch { offset: usize },
     #[error("WU8 file did not contain valid magic")]
     InvalidWU8Magic { found_magic: [u8; 4] },
+    #[error("WU8 file was already decoded into a U8 file")]
+    AlreadyDecoded,
     #[error("U8 file did not contain valid magic")]
     InvalidU8Magic { found_magic: [u8; 4] },
     #[error("WBZ file contained an invalid string")]
     InvalidString(#[from] #[source] std::str::Utf8Error),
+    #[error("A node's name offset pointed outside of the string table")]
+    InvalidStringOffset { offset: u32, table_size: u32 },
+    #[error("A node's name was not null terminated within the string table")]
+    UnterminatedString { offset: u32 },
     #[error("WBZ file contained an invalid boolean")]
     InvalidBool(u8),
+    #[error("U8 header node offset did not point directly after the header")]
+    HeaderOffsetMismatch { expected: u32, found: u32 },
+    #[error("The auto-add library directory does not exist")]
+    AutoAddNotFound(std::path::PathBuf),
+    #[error("The auto-add library did not match the expected fingerprint")]
+    AutoAddMismatch { expected: [u8; 32], found: [u8; 32] },
+    #[error("A node's file data extended past the end of the file")]
+    NodeOutOfBounds { data_offset: u32, size: u32 },
+    #[error("U8 header metadata size extended past the end of the file")]
+    MetaSizeOutOfBounds { node_offset: u32, meta_size: u32 },
+    #[error("U8 header data offset pointed inside the metadata or past the end of the file")]
+    InconsistentHeader { node_offset: u32, meta_size: u32, data_offset: u32 },
+    #[error("Two nodes contained overlapping file data")]
+    OverlappingNodes { first: u32, second: u32 },
+    #[error("File was not a WBZ, WU8, or U8 file")]
+    UnknownFormat { found_magic: [u8; 4] },
+    #[error("SZS file did not contain valid Yaz0 magic")]
+    InvalidYaz0Magic { found_magic: [u8; 4] },
+    #[error("SZS file contained a truncated or corrupt Yaz0 stream")]
+    InvalidYaz0Data,
+    #[error("U8 node table extended past the end of the metadata")]
+    NodeTableOutOfBounds { node_count: u32 },
+    #[error("U8 root node did not count itself in the node table size")]
+    EmptyNodeTable,
+    #[error("A directory node's size pointed outside of the node table")]
+    InvalidDirectorySize { size: u32, node_count: u32 },
+    #[error("The U8 string table is above 16MB in size")]
+    StringTableTooBig,
+    #[error("Failed to read node {index} of the U8 file")]
+    AtNode { index: u32, name: Option<String>, source: Box<Error> },
 }
 
-/// Decompresses a WBZ file into the equivalent U8 file.
+impl Error {
+    /// Returns the kind of the underlying IO error, if this is [`Error::FileOperationFailed`]
+    /// or an [`Error::AtNode`] wrapping one.
+    #[must_use]
+    pub fn io_kind(&self) -> Option<ErrorKind> {
+        match self {
+            Self::FileOperationFailed(err) => Some(err.kind()),
+            Self::AtNode { source, .. } => source.io_kind(),
+            _ => None,
+        }
+    }
+
+    fn unknown_format(file: &[u8]) -> Self {
+        let mut found_magic = [0; 4];
+        let magic_len = file.len().min(4);
+        found_magic[..magic_len].copy_from_slice(&file[..magic_len]);
+
+        Self::UnknownFormat { found_magic }
+    }
+
+    /// Converts an error from a bzip2 stream, separating corrupt or truncated data from IO failures.
+    fn from_bzip(err: std::io::Error) -> Self {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            return Self::BZip(bzip2::Error::Data);
+        }
+
+        match err.get_ref().and_then(|inner| inner.downcast_ref()) {
+            Some(&bzip_err) => Self::BZip(bzip_err),
+            None => Self::FileOperationFailed(err),
+        }
+    }
+}
+
+/// Checks a file of `len` bytes fits within the 4GB limit of the U8 format.
+///
+/// This is cheap to call before reading or decompressing a file, to reject it before doing any work.
 ///
 /// # Errors
-/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file.
+/// Errors with [`Error::FileTooBig`] if `len` is 4GB or above.
+pub fn ensure_size(len: u64) -> Result<(), Error> {
+    u32::try_from(len)?;
+    Ok(())
+}
+
+/// Detects the format of `file` by its magic, returning `None` if the format is not recognised.
+#[must_use]
+pub fn detect_format(file: &[u8]) -> Option<Format> {
+    Format::from_magic(file)
+}
+
+/// Reads only the header of a U8 or WU8 file, without running any passes.
+///
+/// # Errors
+/// Errors if the file is not a U8 or WU8 file, or is too short to contain a header.
 ///
 /// See [`Error`] for all possible failure states.
-pub fn decode_wbz(mut wbz_file: impl Read + Seek, autoadd_path: &Path) -> Result<Vec<u8>, Error> {
-    debug!("Checking signature of WBZ");
-    let mut parser = Parser::new(&mut wbz_file);
-    let magic_bytes = parser.read::<8>()?;
+pub fn read_header(file: &[u8]) -> Result<U8Header, Error> {
+    let expected_magic = match detect_format(file) {
+        Some(Format::U8) => U8_MAGIC,
+        Some(Format::Wu8) => WU8_MAGIC,
+        Some(Format::Wbz) | None => return Err(Error::unknown_format(file)),
+    };
 
-    if magic_bytes != *b"WBZaWU8a" {
-        return Err(Error::InvalidWBZMagic {
-            found_magic: magic_bytes,
-        });
+    Parser::new(Cursor::new(file)).read_u8_header(expected_magic)
+}
+
+/// Reads the location of the node table, string table, and file data of a U8 or WU8 file, without running any passes.
+///
+/// # Errors
+/// Errors if the file is not a U8 or WU8 file, is too short to contain a header and root node,
+/// or if the node table does not fit within the metadata.
+///
+/// See [`Error`] for all possible failure states.
+pub fn archive_layout(file: &[u8]) -> Result<ArchiveLayout, Error> {
+    let header = read_header(file)?;
+    header.check_bounds(file.len())?;
+
+    let root_start = header.node_offset as usize;
+    let mut root_bytes: [u8; 12] = file
+        .get(root_start..root_start + 12)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| std::io::Error::from(ErrorKind::UnexpectedEof))?;
+
+    if header.magic == header.endianness.magic(WU8_MAGIC) {
+        let starting_key = derive_starting_key(file.len().try_into()?);
+        for byte in &mut root_bytes {
+            *byte ^= starting_key;
+        }
     }
 
-    parser.read::<8>()?;
+    let root_node =
+        Parser::with_endianness(Cursor::new(root_bytes), header.endianness).read_root_node()?;
+    let node_table_size = header.node_table_size(root_node.size)?;
 
-    debug!("Decompressing WU8 file");
-    let mut wu8_file = Vec::new();
-    bzip2::read::BzDecoder::new(&mut wbz_file).read_to_end(&mut wu8_file)?;
+    Ok(ArchiveLayout {
+        node_count: root_node.size,
+        node_offset: header.node_offset,
+        node_table_size,
+        string_table_start: header.node_offset + node_table_size,
+        string_table_end: header.node_offset + header.meta_size,
+        data_offset: header.data_offset,
+    })
+}
 
-    decode_wu8(&mut wu8_file, autoadd_path)?;
-    Ok(wu8_file)
+/// Computes a fingerprint of the auto-add library at `autoadd_path`, for checking it matches a known-good library.
+///
+/// The fingerprint is a SHA-256 digest over the sorted relative path and size of every file,
+/// so it does not read any file contents and changes if a file is added, removed, or resized.
+///
+/// Requires the `sha256` feature, which uses the same built-in SHA-256 implementation as [`decode_wbz_hashed`].
+///
+/// # Errors
+/// Errors if `autoadd_path` does not exist or could not be read.
+#[cfg(feature = "sha256")]
+pub fn fingerprint_autoadd(autoadd_path: &Path) -> Result<[u8; 32], Error> {
+    if !autoadd_path.try_exists()? {
+        return Err(Error::AutoAddNotFound(autoadd_path.to_owned()));
+    }
+
+    let mut files = Vec::new();
+    iterator::list_autoadd_files(autoadd_path, "", &mut files)?;
+    files.sort_unstable();
+
+    let mut listing = Vec::new();
+    for (path, size) in files {
+        listing.extend_from_slice(path.as_bytes());
+        listing.push(0);
+        listing.extend_from_slice(&size.to_be_bytes());
+    }
+
+    Ok(sha256::digest(&listing))
 }
 
-/// Compresses a U8 file into the equivalent WBZ file.
+/// Checks the auto-add library at `autoadd_path` matches `expected`, as returned by [`fingerprint_autoadd`].
 ///
-/// `u8_file` will also be mutated to contain the decompressed WU8 file.
+/// Requires the `sha256` feature.
 ///
 /// # Errors
-/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file.
+/// Errors with [`Error::AutoAddMismatch`] if the fingerprint differs, or if `autoadd_path`
+/// does not exist or could not be read.
+#[cfg(feature = "sha256")]
+pub fn check_autoadd(autoadd_path: &Path, expected: [u8; 32]) -> Result<(), Error> {
+    let found = fingerprint_autoadd(autoadd_path)?;
+    if found == expected {
+        Ok(())
+    } else {
+        Err(Error::AutoAddMismatch { expected, found })
+    }
+}
+
+/// Decompresses a WBZ file into the equivalent U8 file.
+///
+/// If `autoadd_path` is `None`, the auto-add library is skipped and every file is decoded with the derived key.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
 ///
 /// See [`Error`] for all possible failure states.
-pub fn encode_wbz(
-    u8_file: &mut [u8],
-    mut wbz_file: impl Write,
-    autoadd_path: &Path,
-) -> Result<(), Error> {
-    debug!("Checking signature of U8 file");
-    let magic_bytes = u8_file[0..4]
-        .try_into()
-        .map_err(|_| std::io::Error::from(ErrorKind::Other))?;
+pub fn decode_wbz(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+) -> Result<Vec<u8>, Error> {
+    decode_wbz_with(
+        wbz_file,
+        ConversionOptions::new().with_autoadd(autoadd_path),
+    )
+}
 
-    if magic_bytes != U8_MAGIC {
-        return Err(Error::InvalidWU8Magic {
-            found_magic: magic_bytes,
-        });
-    }
+/// Decompresses a WBZ file into the equivalent U8 file, calling `progress` after each file is processed.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_with_progress(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    mut progress: impl FnMut(Progress),
+) -> Result<Vec<u8>, Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_progress(&mut progress);
 
-    iterate_wu8(u8_file, autoadd_path, true)?;
-    let wu8_file = u8_file;
-    let wu8_len: u32 = wu8_file.len().try_into()?;
+    decode_wbz_with(wbz_file, options)
+}
 
-    wbz_file
-        .write_all(b"WBZa")
-        .and_then(|()| wbz_file.write_all(&wu8_file[0..8]))
-        .and_then(|()| wbz_file.write_all(&wu8_len.to_be_bytes()))?;
+/// Decompresses a WBZ file into the equivalent U8 file, configured by `options`.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if the auto-add library is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_with(
+    wbz_file: impl Read + Seek,
+    options: ConversionOptions<'_>,
+) -> Result<Vec<u8>, Error> {
+    let mut u8_file = Vec::new();
+    decode_wbz_into_with(wbz_file, options, &mut u8_file)?;
+    Ok(u8_file)
+}
 
-    bzip2::write::BzEncoder::new(wbz_file, bzip2::Compression::best()).write_all(wu8_file)?;
-    Ok(())
+/// Decompresses a WBZ file into the equivalent U8 file, calling `on_chunk` with each block of decompressed
+/// WU8 data and its offset in the WU8 file.
+///
+/// Chunks are passed in order as soon as they are decompressed, before any passes are run, so are still
+/// encoded. This allows showing progress while large files are decompressed, or hashing the WU8 file.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_with_chunks(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    mut on_chunk: impl FnMut(&[u8], u64),
+) -> Result<Vec<u8>, Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_chunks(&mut on_chunk);
+
+    decode_wbz_with(wbz_file, options)
 }
 
-/// Decodes a WU8 file into the equivalent U8 file **in place**.
+/// Decompresses a WBZ file into the equivalent U8 file, using `autoadd_mode` to decide if the auto-add library is used.
 ///
 /// # Errors
-/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file.
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
 ///
 /// See [`Error`] for all possible failure states.
-pub fn decode_wu8(wu8_file: &mut [u8], autoadd_path: &Path) -> Result<(), Error> {
-    iterate_wu8(wu8_file, autoadd_path, false)
+pub fn decode_wbz_with_mode(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    autoadd_mode: AutoAddMode,
+) -> Result<Vec<u8>, Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_autoadd_mode(autoadd_mode);
+
+    decode_wbz_with(wbz_file, options)
 }
 
-/// Encodes a U8 file into the equivalent WU8 file **in place**.
+/// Decompresses a WBZ file into the equivalent U8 file, pushing the path of each file
+/// that was looked up but not found in the auto-add library into `missing_autoadd`.
+///
+/// See [`ConversionOptions::with_missing_autoadd`] for more details.
 ///
 /// # Errors
-/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file.
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_with_missing(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    missing_autoadd: &mut Vec<PathBuf>,
+) -> Result<Vec<u8>, Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_missing_autoadd(missing_autoadd);
+
+    decode_wbz_with(wbz_file, options)
+}
+
+/// Decompresses a WBZ file into the equivalent U8 file, also returning the parsed WBZ header.
+///
+/// [`decode_wbz_into_with`] also returns the header, for use with [`ConversionOptions`].
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic, an unsupported header or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_with_header(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+) -> Result<(Vec<u8>, WbzHeader), Error> {
+    let mut u8_file = Vec::new();
+    let options = ConversionOptions::new().with_autoadd(autoadd_path);
+    let header = decode_wbz_into_with(wbz_file, options, &mut u8_file)?;
+    Ok((u8_file, header))
+}
+
+/// Decompresses a WBZ file into the equivalent U8 file, writing the result into `u8_file`.
+///
+/// `u8_file` is cleared before decoding, so its allocation can be reused across calls
+/// to avoid reallocating for every file in a batch conversion.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
 ///
 /// See [`Error`] for all possible failure states.
-pub fn encode_wu8(u8_file: &mut [u8], autoadd_path: &Path) -> Result<(), Error> {
-    iterate_wu8(u8_file, autoadd_path, true)
+pub fn decode_wbz_into(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    u8_file: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new().with_autoadd(autoadd_path);
+    decode_wbz_into_with(wbz_file, options, u8_file).map(drop)
 }
 
-fn iterate_wu8(file: &mut [u8], autoadd_path: &Path, encode: bool) -> Result<(), Error> {
-    let size: u32 = file.len().try_into()?;
-    let starting_key = derive_starting_key(size);
+/// Decompresses a WBZ file into the equivalent U8 file, writing the result into `u8_file` and
+/// returning the parsed WBZ header, configured by `options`.
+///
+/// See [`decode_wbz_into`] for more details.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// if the checksum does not match, or if the auto-add library is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_into_with(
+    wbz_file: impl Read + Seek,
+    mut options: ConversionOptions<'_>,
+    u8_file: &mut Vec<u8>,
+) -> Result<WbzHeader, Error> {
+    let header = decompress_wbz_with_chunks(wbz_file, u8_file, |chunk, offset| {
+        options.on_chunk(chunk, offset);
+    })?;
+
+    if let Some(expected_checksum) = options.expected_checksum() {
+        let checksum = crc32::checksum(u8_file);
+        if checksum != expected_checksum {
+            return Err(Error::ChecksumMismatch {
+                expected: expected_checksum,
+                found: checksum,
+            });
+        }
+    }
 
-    let mut reader = RefCell::new(Parser::new(Cursor::new(file)));
+    iterate_wu8(u8_file, false, options.pass_options())?;
+    Ok(header)
+}
 
-    debug!("Parsing header");
-    let (starting_key, mut derived_key, header, root_node) = {
-        let reader = reader.get_mut();
-        let header = if encode {
-            reader.read_u8_header::<{ u32::from_le_bytes(U8_MAGIC) }>()?
-        } else {
-            reader.read_u8_header::<{ u32::from_le_bytes(WU8_MAGIC) }>()?
-        };
+/// Decompresses a WBZ file into the equivalent U8 file, writing the result into `u8_file`.
+///
+/// The passes require random access, so the file is still decoded in an internal buffer before being written out.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// if `autoadd_path` is provided but does not exist, or if writing to `u8_file` fails.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_to(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    u8_file: impl Write,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new().with_autoadd(autoadd_path);
+    decode_wbz_to_with(wbz_file, options, u8_file)
+}
+
+/// Decompresses a WBZ file into the equivalent U8 file, writing the result into `u8_file`, configured by `options`.
+///
+/// See [`decode_wbz_to`] for more details.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// if the auto-add library is provided but does not exist, or if writing to `u8_file` fails.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_to_with(
+    wbz_file: impl Read + Seek,
+    options: ConversionOptions<'_>,
+    mut u8_file: impl Write,
+) -> Result<(), Error> {
+    let decoded = decode_wbz_with(wbz_file, options)?;
+    u8_file.write_all(&decoded)?;
+    Ok(())
+}
 
-        let start_pos = reader.position()?;
-        assert_eq!(start_pos, header.node_offset);
+/// Decompresses a WBZ file into the equivalent U8 file, returning the partially converted file on failure.
+///
+/// The files processed before the failure are fully decoded, so can be salvaged with [`U8Archive`]
+/// or [`node_info`] if the node table is intact. With the `parallel` feature, files which are not part
+/// of the auto-add library are only decoded once every file has been processed.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_partial(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+) -> Result<Vec<u8>, PartialError> {
+    let mut buffer = Vec::new();
+    if let Err(source) = decompress_wbz_into(wbz_file, &mut buffer) {
+        return Err(PartialError {
+            processed_nodes: 0,
+            buffer,
+            source,
+        });
+    }
 
-        if !encode {
-            // First pass, XOR all node and string table bytes with base key
-            perform_header_pass(reader.as_mut(), starting_key, start_pos, header.meta_size);
+    let mut processed_nodes = 0;
+    let options = PassOptions::new(autoadd_path);
+    let result = Converter::new(&mut buffer, false, options).and_then(|mut converter| {
+        while let StepResult::Progress(_) = converter.step()? {
+            processed_nodes += 1;
         }
 
-        // Now, get the initial node to find the node table size
-        debug!("Calculating offsets for header data");
-        let root_node = reader.read_node()?;
-        reader.set_position(start_pos)?;
+        Ok(())
+    });
 
-        (starting_key, starting_key, header, root_node)
-    };
+    match result {
+        Ok(()) => Ok(buffer),
+        Err(source) => Err(PartialError {
+            processed_nodes,
+            buffer,
+            source,
+        }),
+    }
+}
 
-    // Calculate the metadata for offsets and sizes
-    let node_header_size = root_node.size * 12;
-    let string_table_start = header.node_offset + node_header_size;
-
-    let mut reader = Rc::new(reader);
-    info!("Starting decode pass 1 (XOR all object files with auto-add library)");
-    for item in U8Iterator::new(
-        reader.clone(),
-        root_node.size,
-        string_table_start,
-        autoadd_path,
-    ) {
-        let (node, name, original_data) = match item {
-            U8NodeItem::File {
-                node,
-                name,
-                original_data: Some(original_data),
-            } => (node, name, original_data),
-            U8NodeItem::Error(err) => return Err(err),
-            _ => continue,
-        };
+fn decompress_wbz(wbz_file: impl Read + Seek) -> Result<(WbzHeader, Vec<u8>), Error> {
+    let mut wu8_file = Vec::new();
+    let header = decompress_wbz_into(wbz_file, &mut wu8_file)?;
+    Ok((header, wu8_file))
+}
 
-        let original_size = original_data.len();
-        derived_key ^= original_data[original_size / 2]
-            ^ original_data[original_size / 3]
-            ^ original_data[original_size / 4];
-
-        debug!("Starting {name} auto-add XOR");
-        perform_pass_one(
-            reader.borrow_mut().as_mut(),
-            &original_data,
-            node,
-            starting_key,
-        );
-    }
-
-    {
-        // get_mut is safe as the only other handle to the Rc was
-        // held by the U8Iterator, which has just been dropped.
-        let file = Rc::get_mut(&mut reader).unwrap().get_mut();
-        file.set_position(header.node_offset)?;
-    }
-
-    info!("Starting pass 2 (XOR all non-object files with derived key {derived_key})");
-
-    for item in U8Iterator::new(
-        reader.clone(),
-        root_node.size,
-        string_table_start,
-        autoadd_path,
-    ) {
-        let (node, name) = match item {
-            U8NodeItem::File {
-                node,
-                name,
-                original_data: None,
-            } => (node, name),
-            U8NodeItem::Error(err) => return Err(err),
-            _ => continue,
-        };
+fn decompress_wbz_into(
+    wbz_file: impl Read + Seek,
+    wu8_file: &mut Vec<u8>,
+) -> Result<WbzHeader, Error> {
+    decompress_wbz_with_chunks(wbz_file, wu8_file, |_, _| {})
+}
+
+fn decompress_wbz_with_chunks(
+    mut wbz_file: impl Read + Seek,
+    wu8_file: &mut Vec<u8>,
+    mut on_chunk: impl FnMut(&[u8], u64),
+) -> Result<WbzHeader, Error> {
+    debug!("Checking signature of WBZ");
+    let mut parser = Parser::new(&mut wbz_file);
+    let magic_bytes = parser.read::<8>()?;
 
-        debug!("Starting {name} XOR");
-        perform_pass_two(reader.borrow_mut().as_mut(), node, derived_key);
+    if magic_bytes != WBZ_MAGIC {
+        return Err(Error::InvalidWBZMagic {
+            found_magic: magic_bytes,
+        });
     }
 
-    if encode {
-        // Last pass, XOR all node and string table bytes with base key
-        let file = Rc::get_mut(&mut reader).unwrap().get_mut().as_mut();
-        perform_header_pass(file, starting_key, header.node_offset, header.meta_size);
+    let header = WbzHeader {
+        node_offset: parser.read_u32()?,
+        wu8_size: parser.read_u32()?,
+    };
+
+    if header.node_offset != WBZ_NODE_OFFSET {
+        return Err(Error::UnsupportedWbzHeader(header));
     }
 
-    let file_refcell = Rc::try_unwrap(reader).unwrap();
-    let file = file_refcell.into_inner().into_inner().into_inner();
+    let position = wbz_file.stream_position()?;
+    let compressed_size = wbz_file.seek(SeekFrom::End(0))?.saturating_sub(position);
+    wbz_file.seek(SeekFrom::Start(position))?;
 
-    // Setup new magic
-    if encode {
-        file[0..4].copy_from_slice(&WU8_MAGIC);
-    } else {
-        file[0..4].copy_from_slice(&U8_MAGIC);
+    debug!("Decompressing WU8 file wu8_size={}", header.wu8_size);
+    let reserve_size = u64::from(header.wu8_size).min(compressed_size * MAX_RESERVE_RATIO);
+    wu8_file.clear();
+    wu8_file.reserve_exact(usize::try_from(reserve_size)?);
+
+    // Reading one byte past the stated size is enough to detect a mismatch, without decompressing the rest.
+    let mut decoder =
+        bzip2::read::BzDecoder::new(&mut wbz_file).take(u64::from(header.wu8_size) + 1);
+
+    loop {
+        let chunk_start = wu8_file.len();
+        let read = (&mut decoder)
+            .take(DECOMPRESS_CHUNK_SIZE)
+            .read_to_end(wu8_file)
+            .map_err(Error::from_bzip)?;
+
+        if read == 0 {
+            break;
+        }
+
+        on_chunk(&wu8_file[chunk_start..], chunk_start as u64);
     }
 
-    Ok(())
+    if wu8_file.len() as u64 != u64::from(header.wu8_size) {
+        return Err(Error::WbzSizeMismatch {
+            expected: header.wu8_size,
+            found: wu8_file.len() as u64,
+        });
+    }
+
+    Ok(header)
+}
+
+/// Decompresses a WBZ file and decodes only the file at `path`, returning `None` if the archive does not contain it.
+///
+/// `path` uses the same format as [`U8Archive::get`], such as `./course.kmp`.
+///
+/// The WBZ file must still be fully decompressed, and files which are not part of the auto-add library
+/// are decoded with the derived key, so every file in the archive is still looked up in the auto-add library.
+/// However, only the requested file's data is decoded.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn extract_file(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    path: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    let (_, mut wu8_file) = decompress_wbz(wbz_file)?;
+    Converter::new(&mut wu8_file, false, PassOptions::new(autoadd_path))?.extract(path)
+}
+
+/// Decompresses a WBZ file and returns the path of every file which will be read from the auto-add library when decoding it.
+///
+/// Paths are relative to the auto-add library, in node table order, such as `./course_model.brres`.
+/// Files which are missing from `autoadd_path` are not included, see [`ConversionOptions::with_missing_autoadd`] to find those instead.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn required_autoadd_files(
+    wbz_file: impl Read + Seek,
+    autoadd_path: &Path,
+) -> Result<Vec<PathBuf>, Error> {
+    let (_, mut wu8_file) = decompress_wbz(wbz_file)?;
+    Converter::new(&mut wu8_file, false, PassOptions::new(Some(autoadd_path)))?.required_autoadd()
+}
+
+/// Checks that a WBZ file survives a round trip through decoding and encoding.
+///
+/// The WBZ file is decoded into a U8 file, which is then encoded back into a WU8 file and compared
+/// against the WU8 file originally decompressed from the WBZ file.
+///
+/// Returns the offset of the first differing byte, or `None` if the round trip reproduced the original WU8 file.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn verify_wbz_roundtrip(
+    wbz_file: &[u8],
+    autoadd_path: Option<&Path>,
+) -> Result<Option<usize>, Error> {
+    let (_, wu8_file) = decompress_wbz(Cursor::new(wbz_file))?;
+
+    let mut roundtrip_file = wu8_file.clone();
+    decode_wu8(&mut roundtrip_file, autoadd_path)?;
+    encode_wu8(&mut roundtrip_file, autoadd_path)?;
+
+    Ok(first_difference(&wu8_file, &roundtrip_file))
+}
+
+/// Returns the offset of the first differing byte, or the length of the shorter file if one is a prefix of the other.
+fn first_difference(first: &[u8], second: &[u8]) -> Option<usize> {
+    let differing = first.iter().zip(second).position(|(a, b)| a != b);
+    differing.or_else(|| (first.len() != second.len()).then(|| first.len().min(second.len())))
+}
+
+/// Compares a WU8 file against a reference, such as one produced by Wiimm's SZS Tools.
+///
+/// This is intended for maintaining compatibility fixtures, so reports the bytes surrounding the
+/// first difference instead of a simple boolean.
+///
+/// # Errors
+/// Errors with the first difference if the files are not byte identical.
+pub fn assert_wu8_matches(ours: &[u8], reference: &[u8]) -> Result<(), Mismatch> {
+    let Some(offset) = first_difference(ours, reference) else {
+        return Ok(());
+    };
+
+    let context_start = offset.saturating_sub(MISMATCH_CONTEXT);
+    let context = |file: &[u8]| {
+        let context_end = (offset + MISMATCH_CONTEXT).min(file.len());
+        file.get(context_start..context_end)
+            .unwrap_or_default()
+            .to_vec()
+    };
+
+    Err(Mismatch {
+        offset,
+        context_start,
+        ours: context(ours),
+        reference: context(reference),
+    })
+}
+
+/// Decompresses an in-memory WBZ file into the equivalent U8 file.
+///
+/// This is equivalent to [`decode_wbz`], without needing to wrap the buffer in a [`Cursor`].
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_bytes(wbz_file: &[u8], autoadd_path: Option<&Path>) -> Result<Vec<u8>, Error> {
+    decode_wbz(Cursor::new(wbz_file), autoadd_path)
+}
+
+/// Decompresses a WBZ file into the equivalent U8 file, returning it alongside its SHA-256 digest.
+///
+/// The digest is computed over the final U8 file, so it will change if the auto-add library does.
+///
+/// Requires the `sha256` feature. The digest is computed by a SHA-256 implementation built into this crate,
+/// so the feature does not add any dependencies.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+#[cfg(feature = "sha256")]
+pub fn decode_wbz_hashed(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+) -> Result<(Vec<u8>, [u8; 32]), Error> {
+    let u8_file = decode_wbz(wbz_file, autoadd_path)?;
+    let digest = sha256::digest(&u8_file);
+    Ok((u8_file, digest))
+}
+
+/// Decompresses a WBZ file and returns the CRC-32 checksum of the WU8 file inside it, see [`decode_wbz_checked`].
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn wbz_checksum(wbz_file: impl Read + Seek) -> Result<u32, Error> {
+    let (_, wu8_file) = decompress_wbz(wbz_file)?;
+    Ok(crc32::checksum(&wu8_file))
+}
+
+/// Decompresses a WBZ file into the equivalent U8 file, first checking the CRC-32 checksum of the WU8 file inside it.
+///
+/// WBZ files do not store a checksum, so `expected_checksum` must come from elsewhere, such as a download
+/// manifest created with [`wbz_checksum`]. The checksum is computed before decoding, so does not depend
+/// on the auto-add library.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// if the checksum does not match, or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_checked(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    expected_checksum: u32,
+) -> Result<Vec<u8>, Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_checksum(expected_checksum);
+
+    decode_wbz_with(wbz_file, options)
+}
+
+/// Decompresses a WBZ file into the equivalent SZS file, which is a U8 file wrapped in Yaz0 compression.
+///
+/// The inner U8 file is identical to the output of [`decode_wbz`], however the Yaz0 stream
+/// may not be byte identical to SZS files produced by other encoders.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_to_szs(
+    wbz_file: impl Read + Seek,
+    autoadd_path: Option<&Path>,
+    compression: Yaz0Compression,
+) -> Result<Vec<u8>, Error> {
+    let options = ConversionOptions::new().with_autoadd(autoadd_path);
+    decode_wbz_to_szs_with(wbz_file, options, compression)
+}
+
+/// Decompresses a WBZ file into the equivalent SZS file, configured by `options`.
+///
+/// See [`decode_wbz_to_szs`] for more details.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file,
+/// or if the auto-add library is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wbz_to_szs_with(
+    wbz_file: impl Read + Seek,
+    options: ConversionOptions<'_>,
+    compression: Yaz0Compression,
+) -> Result<Vec<u8>, Error> {
+    let u8_file = decode_wbz_with(wbz_file, options)?;
+
+    debug!("Compressing U8 file into SZS");
+    yaz0::compress(&u8_file, compression)
+}
+
+/// Compresses a U8 file into the equivalent WBZ file.
+///
+/// `u8_file` will also be mutated to contain the decompressed WU8 file.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wbz(
+    u8_file: &mut [u8],
+    wbz_file: impl Write,
+    autoadd_path: Option<&Path>,
+) -> Result<(), Error> {
+    encode_wbz_with(
+        u8_file,
+        wbz_file,
+        ConversionOptions::new().with_autoadd(autoadd_path),
+    )
+}
+
+/// Compresses a U8 file into the equivalent WBZ file, calling `progress` after each file is processed.
+///
+/// `u8_file` will also be mutated to contain the decompressed WU8 file.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wbz_with_progress(
+    u8_file: &mut [u8],
+    wbz_file: impl Write,
+    autoadd_path: Option<&Path>,
+    mut progress: impl FnMut(Progress),
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_progress(&mut progress);
+
+    encode_wbz_with(u8_file, wbz_file, options)
+}
+
+/// Compresses a U8 file into the equivalent WBZ file, configured by `options`.
+///
+/// `u8_file` will also be mutated to contain the decompressed WU8 file.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file,
+/// or if the auto-add library is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wbz_with(
+    u8_file: &mut [u8],
+    wbz_file: impl Write,
+    mut options: ConversionOptions<'_>,
+) -> Result<(), Error> {
+    ensure_size(u8_file.len() as u64)?;
+
+    debug!("Checking signature of U8 file");
+    let magic_bytes = *u8_file.first_chunk().ok_or(Error::FileTooSmall)?;
+
+    if magic_bytes != U8_MAGIC {
+        return Err(Error::InvalidU8Magic {
+            found_magic: magic_bytes,
+        });
+    }
+
+    let compression = options.compression();
+    iterate_wu8(u8_file, true, options.pass_options())?;
+    write_wbz(u8_file, wbz_file, compression)
+}
+
+/// Compresses a U8 file into the equivalent WBZ file, using `compression` for the bzip2 stream.
+///
+/// [`encode_wbz`] uses [`WbzCompression::best`], while lower levels produce larger files but encode faster.
+///
+/// `u8_file` will also be mutated to contain the decompressed WU8 file.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wbz_with_compression(
+    u8_file: &mut [u8],
+    wbz_file: impl Write,
+    autoadd_path: Option<&Path>,
+    compression: WbzCompression,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_compression(compression);
+
+    encode_wbz_with(u8_file, wbz_file, options)
+}
+
+fn write_wbz(
+    wu8_file: &[u8],
+    mut wbz_file: impl Write,
+    compression: WbzCompression,
+) -> Result<(), Error> {
+    let wu8_len: u32 = wu8_file.len().try_into()?;
+
+    wbz_file
+        .write_all(b"WBZa")
+        .and_then(|()| wbz_file.write_all(&wu8_file[0..8]))
+        .and_then(|()| wbz_file.write_all(&wu8_len.to_be_bytes()))?;
+
+    let compression = bzip2::Compression::new(compression.level());
+    bzip2::write::BzEncoder::new(wbz_file, compression).write_all(wu8_file)?;
+    Ok(())
+}
+
+/// Compresses a U8 file into the equivalent WBZ file, without mutating `u8_file`.
+///
+/// This copies `u8_file` into a new buffer before encoding, so if the U8 file is not needed
+/// afterwards, [`encode_wbz`] avoids the extra allocation and copy.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wbz_owned(u8_file: &[u8], autoadd_path: Option<&Path>) -> Result<Vec<u8>, Error> {
+    let mut wu8_file = u8_file.to_vec();
+    let mut wbz_file = Vec::new();
+
+    encode_wbz(&mut wu8_file, &mut wbz_file, autoadd_path)?;
+    Ok(wbz_file)
+}
+
+/// Compresses a U8 file read from `u8_file` into the equivalent WBZ file.
+///
+/// The passes require random access, so the U8 file is first read into an internal buffer.
+///
+/// # Errors
+/// Errors if reading from `u8_file` fails, or if the file is an invalid U8 file, which includes
+/// invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wbz_from_reader(
+    u8_file: impl Read,
+    wbz_file: impl Write,
+    autoadd_path: Option<&Path>,
+) -> Result<(), Error> {
+    let mut buffer = Vec::new();
+    u8_file.take(MAX_FILE_SIZE).read_to_end(&mut buffer)?;
+
+    encode_wbz(&mut buffer, wbz_file, autoadd_path)
+}
+
+/// Compresses an already encoded WU8 file into the equivalent WBZ file, without running any passes.
+///
+/// # Errors
+/// Errors if the file does not start with a valid WU8 header, if the header would be rejected
+/// by [`decode_wbz`], or if the file is too large.
+///
+/// See [`Error`] for all possible failure states.
+pub fn wrap_wu8_as_wbz(wu8_file: &[u8], wbz_file: impl Write) -> Result<(), Error> {
+    let header = Parser::new(Cursor::new(wu8_file)).read_u8_header(WU8_MAGIC)?;
+    if header.endianness != Endianness::Big {
+        // The WBZ magic includes the WU8 magic, so can only contain big-endian files.
+        return Err(Error::InvalidWU8Magic {
+            found_magic: header.magic,
+        });
+    }
+
+    // Run the same checks as decoding, so the WBZ file can always be decoded again.
+    if header.node_offset != WBZ_NODE_OFFSET {
+        return Err(Error::UnsupportedWbzHeader(WbzHeader {
+            node_offset: header.node_offset,
+            wu8_size: wu8_file.len().try_into()?,
+        }));
+    }
+
+    header.check_bounds(wu8_file.len())?;
+    write_wbz(wu8_file, wbz_file, WbzCompression::default())
+}
+
+/// Decompresses a WBZ file into the still encoded WU8 file inside it, without running any passes.
+///
+/// This is the reverse of [`wrap_wu8_as_wbz`], and does not need the auto-add library.
+///
+/// # Errors
+/// Errors if the file is an invalid WBZ file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn unwrap_wbz_as_wu8(wbz_file: impl Read + Seek) -> Result<Vec<u8>, Error> {
+    decompress_wbz(wbz_file).map(|(_, wu8_file)| wu8_file)
+}
+
+/// Compresses a SZS file into the equivalent WBZ file.
+///
+/// The SZS file is decompressed from Yaz0 into a U8 file before being encoded with [`encode_wbz`].
+///
+/// # Errors
+/// Errors if the file is an invalid SZS file, which includes invalid magic or a truncated Yaz0 stream,
+/// or if the contained U8 file is invalid.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_szs(
+    szs_file: &[u8],
+    wbz_file: impl Write,
+    autoadd_path: Option<&Path>,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new().with_autoadd(autoadd_path);
+    encode_szs_with(szs_file, wbz_file, options)
+}
+
+/// Compresses a SZS file into the equivalent WBZ file, configured by `options`.
+///
+/// See [`encode_szs`] for more details.
+///
+/// # Errors
+/// Errors if the file is an invalid SZS file, which includes invalid magic or a truncated Yaz0 stream,
+/// if the contained U8 file is invalid, or if the auto-add library is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_szs_with(
+    szs_file: &[u8],
+    wbz_file: impl Write,
+    options: ConversionOptions<'_>,
+) -> Result<(), Error> {
+    debug!("Decompressing SZS file into U8");
+    let mut u8_file = yaz0::decompress(szs_file)?;
+
+    encode_wbz_with(&mut u8_file, wbz_file, options)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**.
+///
+/// If `autoadd_path` is `None`, the auto-add library is skipped and every file is decoded with the derived key.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8(wu8_file: &mut [u8], autoadd_path: Option<&Path>) -> Result<(), Error> {
+    decode_wu8_with(
+        wu8_file,
+        ConversionOptions::new().with_autoadd(autoadd_path),
+    )
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, configured by `options`.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if the auto-add library is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with(
+    wu8_file: &mut [u8],
+    mut options: ConversionOptions<'_>,
+) -> Result<(), Error> {
+    iterate_wu8(wu8_file, false, options.pass_options()).map(drop)
+}
+
+/// Decodes an owned WU8 file into the equivalent U8 file, returning the same buffer.
+///
+/// This is equivalent to [`decode_wu8`], but allows chaining when the WU8 file is already owned.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_vec(
+    mut wu8_file: Vec<u8>,
+    autoadd_path: Option<&Path>,
+) -> Result<Vec<u8>, Error> {
+    decode_wu8(&mut wu8_file, autoadd_path)?;
+    Ok(wu8_file)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, calling `progress` after each file is processed.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_progress(
+    wu8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    mut progress: impl FnMut(Progress),
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_progress(&mut progress);
+
+    decode_wu8_with(wu8_file, options)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, using `autoadd_mode` to decide if the auto-add library is used.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_mode(
+    wu8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    autoadd_mode: AutoAddMode,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_autoadd_mode(autoadd_mode);
+
+    decode_wu8_with(wu8_file, options)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, pushing the path of each file
+/// that was looked up but not found in the auto-add library into `missing_autoadd`.
+///
+/// Files which are not part of the auto-add library are expected to be missing, so this list is
+/// most useful when compared against the files an archive is known to share with the library.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_missing(
+    wu8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    missing_autoadd: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_missing_autoadd(missing_autoadd);
+
+    decode_wu8_with(wu8_file, options)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, returning the key derived
+/// from the starting key and the auto-add library.
+///
+/// This is the key used to XOR every file not found in the auto-add library, so comparing it
+/// against a known-good value is useful for detecting a mismatched auto-add library.
+///
+/// The starting key is the XOR of the four bytes of the file size. The derived key is then the
+/// starting key combined using XOR with `data[n / 2] ^ data[n / 3] ^ data[n / 4]` for every
+/// non-empty auto-add file of `n` bytes used by the archive.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_key(wu8_file: &mut [u8], autoadd_path: Option<&Path>) -> Result<u8, Error> {
+    let mut options = ConversionOptions::new().with_autoadd(autoadd_path);
+    iterate_wu8(wu8_file, false, options.pass_options())
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, calling `observer` after each
+/// file is decoded using the auto-add library.
+///
+/// `observer` is called with the file name, the file size in bytes, and the derived key after
+/// including that file, allowing inspection of which files contribute to the final key.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_observer(
+    wu8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    mut observer: impl FnMut(&str, u64, u8),
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_observer(&mut observer);
+
+    decode_wu8_with(wu8_file, options)
+}
+
+/// Encodes a U8 file into the equivalent WU8 file **in place**.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wu8(u8_file: &mut [u8], autoadd_path: Option<&Path>) -> Result<(), Error> {
+    encode_wu8_with(u8_file, ConversionOptions::new().with_autoadd(autoadd_path))
+}
+
+/// Encodes a U8 file into the equivalent WU8 file **in place**, configured by `options`.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file,
+/// or if the auto-add library is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wu8_with(
+    u8_file: &mut [u8],
+    mut options: ConversionOptions<'_>,
+) -> Result<(), Error> {
+    iterate_wu8(u8_file, true, options.pass_options()).map(drop)
+}
+
+/// Converts a U8 or WU8 file **in place**, encoding U8 files and decoding WU8 files based on their magic.
+///
+/// # Errors
+/// Errors if the file is not a U8 or WU8 file, or if the conversion fails.
+///
+/// See [`Error`] for all possible failure states.
+pub fn transcode_u8_wu8(file: &mut [u8], autoadd_path: Option<&Path>) -> Result<(), Error> {
+    match detect_format(file) {
+        Some(Format::U8) => encode_wu8(file, autoadd_path),
+        Some(Format::Wu8) => decode_wu8(file, autoadd_path),
+        Some(Format::Wbz) | None => Err(Error::unknown_format(file)),
+    }
+}
+
+/// Encodes a U8 file into the equivalent WU8 file **in place**, calling `progress` after each file is processed.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wu8_with_progress(
+    u8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    mut progress: impl FnMut(Progress),
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_progress(&mut progress);
+
+    encode_wu8_with(u8_file, options)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, using `starting_key` instead of
+/// deriving the starting key from the file size if provided.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_starting_key(
+    wu8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    starting_key: Option<u8>,
+) -> Result<(), Error> {
+    let mut options = ConversionOptions::new().with_autoadd(autoadd_path);
+    if let Some(starting_key) = starting_key {
+        options = options.with_key(starting_key);
+    }
+
+    decode_wu8_with(wu8_file, options)
+}
+
+/// Encodes a U8 file into the equivalent WU8 file **in place**, using `starting_key` instead of
+/// deriving the starting key from the file size if provided.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wu8_with_starting_key(
+    u8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    starting_key: Option<u8>,
+) -> Result<(), Error> {
+    let mut options = ConversionOptions::new().with_autoadd(autoadd_path);
+    if let Some(starting_key) = starting_key {
+        options = options.with_key(starting_key);
+    }
+
+    encode_wu8_with(u8_file, options)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, skipping the header pass if `header_pass` is `false`.
+///
+/// The header pass decodes the node and string tables, so should only be skipped for files
+/// produced by tools which leave them unencoded.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_header_pass(
+    wu8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    header_pass: bool,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_header_pass(header_pass);
+
+    decode_wu8_with(wu8_file, options)
+}
+
+/// Encodes a U8 file into the equivalent WU8 file **in place**, skipping the header pass if `header_pass` is `false`.
+///
+/// The header pass encodes the node and string tables, so should only be skipped when
+/// producing files for tools which expect them to be unencoded.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file,
+/// or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wu8_with_header_pass(
+    u8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+    header_pass: bool,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_header_pass(header_pass);
+
+    encode_wu8_with(u8_file, options)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, first checking that no two files have overlapping data.
+///
+/// Overlapping files would have their shared bytes XOR-ed multiple times, silently corrupting them,
+/// so this check is useful for hand-crafted archives at the cost of an extra walk of the node table.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic, overlapping file data
+/// or a too large file, or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_overlap_check(
+    wu8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_overlap_check(true);
+
+    decode_wu8_with(wu8_file, options)
+}
+
+/// Encodes a U8 file into the equivalent WU8 file **in place**, first checking that no two files have overlapping data.
+///
+/// See [`decode_wu8_with_overlap_check`] for more details.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic, overlapping file data
+/// or a too large file, or if `autoadd_path` is provided but does not exist.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wu8_with_overlap_check(
+    u8_file: &mut [u8],
+    autoadd_path: Option<&Path>,
+) -> Result<(), Error> {
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_overlap_check(true);
+
+    encode_wu8_with(u8_file, options)
+}
+
+/// Decodes a WU8 file into the equivalent U8 file **in place**, reading auto-add files with `resolver`
+/// instead of from a directory.
+///
+/// `resolver` is called with the path of each file in the archive, such as `./course.kmp`,
+/// and should return `None` if the file is not part of the auto-add library.
+///
+/// # Errors
+/// Errors if the file is an invalid WU8 file, which includes invalid magic or a too large file,
+/// or if `resolver` returns an error.
+///
+/// See [`Error`] for all possible failure states.
+pub fn decode_wu8_with_resolver(
+    wu8_file: &mut [u8],
+    resolver: impl Fn(&Path) -> std::io::Result<Option<Vec<u8>>>,
+) -> Result<(), Error> {
+    decode_wu8_with(wu8_file, ConversionOptions::new().with_resolver(&resolver))
+}
+
+/// Encodes a U8 file into the equivalent WU8 file **in place**, reading auto-add files with `resolver`
+/// instead of from a directory.
+///
+/// See [`decode_wu8_with_resolver`] for more details.
+///
+/// # Errors
+/// Errors if the file is an invalid U8 file, which includes invalid magic or a too large file,
+/// or if `resolver` returns an error.
+///
+/// See [`Error`] for all possible failure states.
+pub fn encode_wu8_with_resolver(
+    u8_file: &mut [u8],
+    resolver: impl Fn(&Path) -> std::io::Result<Option<Vec<u8>>>,
+) -> Result<(), Error> {
+    encode_wu8_with(u8_file, ConversionOptions::new().with_resolver(&resolver))
+}
+
+/// Converts the file at `input`, detecting the format by its magic.
+///
+/// WBZ and WU8 files are decoded into U8 files, and U8 files are encoded into WBZ files.
+///
+/// # Errors
+/// Errors if the file cannot be read, is not a WBZ, WU8, or U8 file, or if the conversion fails.
+///
+/// See [`Error`] for all possible failure states.
+pub fn convert_file(input: &Path, autoadd_path: Option<&Path>) -> Result<Vec<u8>, Error> {
+    convert_file_with_progress(input, autoadd_path, |_| {})
+}
+
+/// Converts the file at `input`, detecting the format by its magic and calling `progress` after each file is processed.
+///
+/// See [`convert_file`] for more details.
+///
+/// # Errors
+/// Errors if the file cannot be read, is not a WBZ, WU8, or U8 file, or if the conversion fails.
+///
+/// See [`Error`] for all possible failure states.
+pub fn convert_file_with_progress(
+    input: &Path,
+    autoadd_path: Option<&Path>,
+    mut progress: impl FnMut(Progress),
+) -> Result<Vec<u8>, Error> {
+    let mut file = std::fs::read(input)?;
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_progress(&mut progress);
+
+    match detect_format(&file) {
+        Some(Format::Wbz) => decode_wbz_with(Cursor::new(file), options),
+        Some(Format::Wu8) => {
+            decode_wu8_with(&mut file, options)?;
+            Ok(file)
+        }
+        Some(Format::U8) => {
+            let mut wbz_file = Vec::new();
+            encode_wbz_with(&mut file, &mut wbz_file, options)?;
+            Ok(wbz_file)
+        }
+        None => Err(Error::unknown_format(&file)),
+    }
+}
+
+/// The configuration for a single run of the WU8 passes.
+struct PassOptions<'a> {
+    autoadd: Option<AutoAdd<'a>>,
+    autoadd_mode: AutoAddMode,
+    progress: Option<&'a mut dyn FnMut(Progress)>,
+    missing_autoadd: Option<&'a mut Vec<PathBuf>>,
+    observer: Option<FileObserver<'a>>,
+    starting_key: Option<u8>,
+    header_pass: bool,
+    check_overlaps: bool,
+}
+
+impl<'a> PassOptions<'a> {
+    fn new(autoadd_path: Option<&'a Path>) -> Self {
+        Self {
+            autoadd: autoadd_path.map(AutoAdd::Dir),
+            autoadd_mode: AutoAddMode::Always,
+            progress: None,
+            missing_autoadd: None,
+            observer: None,
+            starting_key: None,
+            header_pass: true,
+            check_overlaps: false,
+        }
+    }
+}
+
+fn iterate_wu8<'a>(
+    file: &'a mut [u8],
+    encode: bool,
+    mut options: PassOptions<'a>,
+) -> Result<u8, Error> {
+    let mut progress = options.progress.take();
+    let mut converter = Converter::new(file, encode, options)?;
+
+    while let StepResult::Progress(step_progress) = converter.step()? {
+        if let Some(callback) = &mut progress {
+            callback(step_progress);
+        }
+    }
+
+    Ok(converter.derived_key())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wbz_size_is_not_trusted() {
+        let mut wbz_file = WBZ_MAGIC.to_vec();
+        wbz_file.extend_from_slice(&WBZ_NODE_OFFSET.to_be_bytes());
+        wbz_file.extend_from_slice(&u32::MAX.to_be_bytes());
+        wbz_file.extend_from_slice(b"BZh91AY&SY");
+        assert_eq!(wbz_file.len(), 26);
+
+        let mut wu8_file = Vec::new();
+        assert!(decompress_wbz_into(Cursor::new(&wbz_file), &mut wu8_file).is_err());
+        assert!(wu8_file.capacity() as u64 <= 10 * MAX_RESERVE_RATIO);
+    }
+
+    /// Creates an auto-add library which is unique to this test, containing some of [`sample_u8`]'s files.
+    fn autoadd_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wbz_converter_{}_{name}", std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("posteffect")).unwrap();
+        std::fs::write(dir.join("course.kmp"), b"auto-add kmp").unwrap();
+        std::fs::write(dir.join("posteffect/posteffect.bfg"), b"auto-add bfg").unwrap();
+        dir
+    }
+
+    fn sample_u8() -> Vec<u8> {
+        U8Builder::new()
+            .add_file("./course.kmp", *b"track kmp")
+            .add_file("./course_model.brres", b"brres".repeat(20))
+            .add_file("./posteffect/posteffect.bfg", *b"bfg")
+            .add_file("./posteffect/posteffect.blight", *b"blight")
+            .finish()
+            .unwrap()
+    }
+
+    #[test]
+    fn conversions_are_deterministic() {
+        let dir = autoadd_dir("deterministic");
+        let u8_file = sample_u8();
+
+        let encode = || {
+            let mut wbz_file = Vec::new();
+            encode_wbz(&mut u8_file.clone(), &mut wbz_file, Some(&dir)).unwrap();
+            wbz_file
+        };
+
+        let wbz_file = encode();
+        assert_eq!(encode(), wbz_file);
+
+        let decode = || decode_wbz(Cursor::new(&wbz_file), Some(&dir)).unwrap();
+        assert_eq!(decode(), decode());
+        assert_eq!(decode(), u8_file);
+
+        // The auto-add library was used, so is needed to decode the file.
+        assert_ne!(decode_wbz(Cursor::new(&wbz_file), None).unwrap(), u8_file);
+
+        let szs_file = yaz0::compress(&u8_file, Yaz0Compression::best()).unwrap();
+        let encode_from_szs = || {
+            let mut szs_wbz_file = Vec::new();
+            encode_szs(&szs_file, &mut szs_wbz_file, Some(&dir)).unwrap();
+            szs_wbz_file
+        };
+
+        assert_eq!(encode_from_szs(), encode_from_szs());
+        assert_eq!(encode_from_szs(), wbz_file);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Builds an archive with a tree shape, names, and contents chosen by `seed`.
+    #[allow(clippy::cast_possible_truncation)]
+    fn random_u8(seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        let mut next = move |below: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % below
+        };
+
+        // Include the auto-add library's files, so they are sometimes taken from it.
+        let mut builder = U8Builder::new();
+        builder.add_file("./course.kmp", *b"track kmp");
+        for index in 0..next(24) {
+            let mut path = String::from(".");
+            for _ in 0..next(5) {
+                path.push_str(["/d0", "/d1", "/d2"][next(3) as usize]);
+            }
+
+            let data: Vec<u8> = (0..next(300)).map(|_| next(4) as u8).collect();
+            builder.add_file(&format!("{path}/f{index}"), data);
+        }
+
+        if next(2) == 0 {
+            builder.add_file("./posteffect/posteffect.bfg", *b"bfg");
+        }
+
+        builder.alignment(next(0x40) as u32).finish().unwrap()
+    }
+
+    #[test]
+    fn random_archives_round_trip() {
+        let dir = autoadd_dir("round_trip");
+        for seed in 0..64 {
+            let u8_file = random_u8(seed);
+
+            for autoadd_path in [None, Some(dir.as_path())] {
+                let mut wu8_file = u8_file.clone();
+                encode_wu8(&mut wu8_file, autoadd_path).unwrap();
+                decode_wu8(&mut wu8_file, autoadd_path).unwrap();
+                assert_eq!(wu8_file, u8_file, "seed {seed}");
+            }
+
+            let mut wbz_file = Vec::new();
+            encode_wbz(&mut u8_file.clone(), &mut wbz_file, Some(&dir)).unwrap();
+            let decoded = decode_wbz(Cursor::new(&wbz_file), Some(&dir)).unwrap();
+            assert_eq!(decoded, u8_file, "seed {seed}");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_wbz_is_bzip_error() {
+        let mut wbz_file = Vec::new();
+        encode_wbz(&mut sample_u8(), &mut wbz_file, None).unwrap();
+
+        let truncated = &wbz_file[..wbz_file.len() / 2];
+        assert!(matches!(
+            decode_wbz(Cursor::new(truncated), None),
+            Err(Error::BZip(_))
+        ));
+
+        let mut corrupted = wbz_file.clone();
+        corrupted[wbz_file.len() / 2] ^= 0xFF;
+        assert!(matches!(
+            decode_wbz(Cursor::new(&corrupted), None),
+            Err(Error::BZip(_))
+        ));
+    }
+
+    #[test]
+    fn header_pass_can_be_skipped() {
+        let u8_file = sample_u8();
+        let meta = read_header(&u8_file).unwrap().meta_range();
+
+        let mut wu8_file = u8_file.clone();
+        encode_wu8_with(&mut wu8_file, ConversionOptions::new()).unwrap();
+        assert_ne!(wu8_file[meta.clone()], u8_file[meta.clone()]);
+
+        let mut wu8_file = u8_file.clone();
+        let options = ConversionOptions::new().with_header_pass(false);
+        encode_wu8_with(&mut wu8_file, options).unwrap();
+        assert_eq!(wu8_file[meta.clone()], u8_file[meta.clone()]);
+        assert_ne!(wu8_file[meta.end..], u8_file[meta.end..]);
+
+        let options = ConversionOptions::new().with_header_pass(false);
+        decode_wu8_with(&mut wu8_file, options).unwrap();
+        assert_eq!(wu8_file, u8_file);
+    }
+
+    #[test]
+    fn directory_size_past_node_table_is_rejected() {
+        let u8_file = sample_u8();
+        let set_dir_size = |size: u32| {
+            // Node 4 is the `posteffect` directory, whose children end the node table.
+            let mut u8_file = u8_file.clone();
+            u8_file[0x20 + 4 * 12 + 8..0x20 + 4 * 12 + 12].copy_from_slice(&size.to_be_bytes());
+            u8_file
+        };
+
+        let mut u8_file = set_dir_size(7);
+        U8Archive::new(&u8_file).unwrap();
+        encode_wu8(&mut u8_file, None).unwrap();
+
+        let mut u8_file = set_dir_size(8);
+        let is_invalid_size = |result: Result<_, Error>| match result {
+            Err(Error::AtNode { index, source, .. }) => {
+                index == 4
+                    && matches!(
+                        *source,
+                        Error::InvalidDirectorySize {
+                            size: 8,
+                            node_count: 7
+                        }
+                    )
+            }
+            _ => false,
+        };
+
+        assert!(is_invalid_size(U8Archive::new(&u8_file).map(drop)));
+        assert!(is_invalid_size(scan_u8(&u8_file).map(drop)));
+        assert!(is_invalid_size(encode_wu8(&mut u8_file, None)));
+    }
+
+    #[test]
+    #[cfg(feature = "sha256")]
+    fn autoadd_fingerprint() {
+        let dir = autoadd_dir("fingerprint");
+        let fingerprint = fingerprint_autoadd(&dir).unwrap();
+        assert_eq!(fingerprint_autoadd(&dir).unwrap(), fingerprint);
+        check_autoadd(&dir, fingerprint).unwrap();
+
+        // Only the path and size of each file are included.
+        std::fs::write(dir.join("course.kmp"), b"auto-add KMP").unwrap();
+        check_autoadd(&dir, fingerprint).unwrap();
+
+        std::fs::write(dir.join("course.kmp"), b"resized kmp").unwrap();
+        assert!(matches!(
+            check_autoadd(&dir, fingerprint),
+            Err(Error::AutoAddMismatch { expected, .. }) if expected == fingerprint
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            fingerprint_autoadd(&dir),
+            Err(Error::AutoAddNotFound(_))
+        ));
+    }
+
+    fn sample_wu8() -> Vec<u8> {
+        let mut wu8_file = U8Builder::new()
+            .add_file("./course.kmp", *b"kmp")
+            .finish()
+            .unwrap();
+
+        encode_wu8(&mut wu8_file, None).unwrap();
+        wu8_file
+    }
+
+    #[test]
+    fn wrap_wu8_round_trip() {
+        let wu8_file = sample_wu8();
+
+        let mut wbz_file = Vec::new();
+        wrap_wu8_as_wbz(&wu8_file, &mut wbz_file).unwrap();
+
+        let decompressed = unwrap_wbz_as_wu8(Cursor::new(&wbz_file)).unwrap();
+        assert_eq!(decompressed, wu8_file);
+    }
+
+    #[test]
+    fn wrap_wu8_validates_header() {
+        let mut wu8_file = sample_wu8();
+        wu8_file[4..8].copy_from_slice(&0x24_u32.to_be_bytes());
+        assert!(matches!(
+            wrap_wu8_as_wbz(&wu8_file, Vec::new()),
+            Err(Error::UnsupportedWbzHeader(WbzHeader {
+                node_offset: 0x24,
+                ..
+            }))
+        ));
+
+        let mut wu8_file = sample_wu8();
+        wu8_file[8..12].copy_from_slice(&0x1000_u32.to_be_bytes());
+        assert!(matches!(
+            wrap_wu8_as_wbz(&wu8_file, Vec::new()),
+            Err(Error::MetaSizeOutOfBounds { .. })
+        ));
+    }
 }
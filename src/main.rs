@@ -1,5 +1,165 @@
 #[cfg(feature = "bin")]
-use std::path::Path;
+use std::{
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "bin")]
+use wbz_converter::{ConversionOptions, Error, Format};
+
+#[cfg(feature = "bin")]
+const DEFAULT_AUTOADD_PATH: &str = "/usr/local/share/szs/auto-add/";
+
+#[cfg(feature = "bin")]
+const YAZ0_MAGIC: &[u8] = b"Yaz0";
+
+/// The length of the longest magic, which is enough to detect the format of a file.
+#[cfg(feature = "bin")]
+const WBZ_MAGIC_LEN: u64 = 8;
+
+/// The output path which writes to stdout instead of a file.
+#[cfg(feature = "bin")]
+const STDOUT_PATH: &str = "-";
+
+#[cfg(feature = "bin")]
+const USAGE: &str = "\
+Usage: wbz-converter [COMMAND] <PATH> [OPTIONS]
+
+Commands:
+  decode   Decodes a WBZ file into a SZS file, or a WU8 file into a U8 file
+  encode   Encodes a SZS or U8 file into a WBZ file
+  inspect  Prints the layout and file listing of a WBZ, WU8, or U8 file
+  verify   Checks a WBZ file is unchanged after decoding and encoding it again
+
+Without a command, the file is decoded or encoded based on its format,
+and every file in a directory is converted.
+
+Options:
+  --autoadd <PATH>     The auto-add library directory
+  --output <PATH>      The output file, defaulting to the input file with a new extension
+  --stdout             Writes the output to stdout and logs to stderr, the same as `--output -`
+  --u8                 Decodes WBZ files into U8 files instead of SZS files
+  --verify             Checks each converted file reproduces the original WU8 file
+  --log-level <LEVEL>  One of off, error, warn, info, debug, or trace, defaulting to debug
+  --help               Prints this message";
+
+#[cfg(feature = "bin")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Convert,
+    Decode,
+    Encode,
+    Inspect,
+    Verify,
+}
+
+#[cfg(feature = "bin")]
+struct Args {
+    command: Command,
+    input: PathBuf,
+    autoadd_path: Option<PathBuf>,
+    output: Option<PathBuf>,
+    u8_output: bool,
+    verify: bool,
+    log_level: log::LevelFilter,
+}
+
+#[cfg(feature = "bin")]
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut command = Command::Convert;
+    let mut input = None;
+    let mut autoadd_path = None;
+    let mut output = None;
+    let mut u8_output = false;
+    let mut verify = false;
+    let mut log_level = log::LevelFilter::Debug;
+
+    let mut args = args.into_iter().peekable();
+    if let Some(arg) = args.peek() {
+        let subcommand = match arg.as_str() {
+            "decode" => Some(Command::Decode),
+            "encode" => Some(Command::Encode),
+            "inspect" => Some(Command::Inspect),
+            "verify" => Some(Command::Verify),
+            _ => None,
+        };
+
+        if let Some(subcommand) = subcommand {
+            command = subcommand;
+            args.next();
+        }
+    }
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or(format!("{arg} must be followed by a value"))
+        };
+        match arg.as_str() {
+            "--help" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            "--autoadd" => autoadd_path = Some(PathBuf::from(value()?)),
+            "--output" => output = Some(PathBuf::from(value()?)),
+            "--stdout" => output = Some(PathBuf::from(STDOUT_PATH)),
+            "--u8" => u8_output = true,
+            "--verify" => verify = true,
+            "--log-level" => {
+                let level = value()?;
+                log_level = level
+                    .parse()
+                    .map_err(|_| format!("Unknown log level {level}"))?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {arg}")),
+            _ if input.is_some() => return Err(format!("Unexpected argument {arg}")),
+            _ => input = Some(PathBuf::from(arg)),
+        }
+    }
+
+    Ok(Args {
+        command,
+        input: input.ok_or("A path to a file or directory must be provided")?,
+        autoadd_path,
+        output,
+        u8_output,
+        verify,
+        log_level,
+    })
+}
+
+/// Returns the percentage of files processed, which is 100% for an archive without any files.
+#[cfg(feature = "bin")]
+fn progress_percent(progress: wbz_converter::Progress) -> u64 {
+    let files_total = u64::from(progress.files_total);
+    if files_total == 0 {
+        return 100;
+    }
+
+    u64::from(progress.files_done) * 100 / files_total
+}
+
+/// Checks `args` can be used to convert every file in the directory at `args.input`.
+#[cfg(feature = "bin")]
+fn check_directory_args(args: &Args) -> Result<(), String> {
+    if args.command != Command::Convert || args.output.is_some() {
+        return Err(
+            "Directories can only be converted without a command, `--output`, or `--stdout`".into(),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "bin")]
+fn print_progress(progress: wbz_converter::Progress) {
+    let percent = progress_percent(progress);
+    log::info!(
+        "Pass {}: {percent}% ({} bytes)",
+        progress.pass,
+        progress.bytes_done
+    );
+}
 
 #[cfg(not(feature = "bin"))]
 fn main() {
@@ -7,47 +167,541 @@ fn main() {
 }
 
 #[cfg(feature = "bin")]
-fn main() -> Result<(), wbz_converter::Error> {
-    use std::io::{Cursor, Read};
+fn main() -> Result<(), Error> {
+    let mut args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
 
     let colours = fern::colors::ColoredLevelConfig::new();
-    fern::Dispatch::new()
+    let logger = fern::Dispatch::new()
         .format(move |out, msg, rec| {
             out.finish(format_args!("[{}] {}", colours.color(rec.level()), msg));
         })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
-        .apply()
-        .unwrap();
+        .level(args.log_level);
 
-    let mut filename = std::env::args()
-        .nth(1)
-        .map(std::path::PathBuf::from)
-        .expect("First argument must be a path to a file");
+    // Logs would corrupt the converted file if it is written to stdout.
+    let to_stdout = args.output.as_deref() == Some(Path::new(STDOUT_PATH));
+    if to_stdout {
+        logger.chain(std::io::stderr()).apply().unwrap();
+    } else {
+        logger.chain(std::io::stdout()).apply().unwrap();
+    }
 
-    let autoadd_path = Path::new("/usr/local/share/szs/auto-add/");
-    let mut in_file = std::fs::File::open(&filename).unwrap();
-    let (out_file, ext) = if filename.extension() == Some("u8".as_ref()) {
-        let mut in_buf = Vec::new();
-        let mut out_file = Cursor::new(Vec::new());
-        in_file.read_to_end(&mut in_buf).unwrap();
+    if args.command == Command::Inspect {
+        return inspect(&args.input);
+    }
 
-        wbz_converter::encode_wbz(&mut in_buf, &mut out_file, autoadd_path)?;
-        (out_file.into_inner(), ".wbz")
-    } else {
-        let out = wbz_converter::decode_wbz(
-            &std::fs::File::open(&filename).expect("Unable to open WBZ file"),
+    let autoadd_path = args
+        .autoadd_path
+        .take()
+        .or_else(|| std::env::var_os("SZS_AUTOADD").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_AUTOADD_PATH));
+
+    if !autoadd_path.is_dir() {
+        log::error!(
+            "Could not find the auto-add library at {}, pass `--autoadd <path>` or set `SZS_AUTOADD` \
+            to the auto-add directory of your Wiimm's SZS Tools install",
+            autoadd_path.display()
+        );
+        std::process::exit(1);
+    }
+
+    let autoadd_path = Some(autoadd_path.as_path());
+    if args.command == Command::Verify {
+        return verify(&args.input, autoadd_path);
+    }
+
+    if !args.input.is_dir() {
+        return convert(
+            args.input,
+            args.command,
             autoadd_path,
-        )?;
+            args.output.as_deref(),
+            args.u8_output,
+            args.verify,
+        );
+    }
+
+    if let Err(err) = check_directory_args(&args) {
+        log::error!("{err}");
+        std::process::exit(2);
+    }
+
+    let mut inputs = std::fs::read_dir(&args.input)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    inputs.retain(|input| {
+        let is_convertible = input.extension().is_some_and(|ext| {
+            ext == Format::Wbz.extension() || ext == "szs" || ext == Format::U8.extension()
+        });
+
+        input.is_file() && is_convertible
+    });
+    inputs.sort();
+
+    // Every output is planned first, so no conversion can overwrite a file which is still to be converted.
+    // Files which cannot be read are left to fail when they are converted.
+    let outputs: Vec<_> = inputs
+        .iter()
+        .map(|input| planned_output(input, args.u8_output).unwrap_or_default())
+        .collect();
+
+    let mut successes = 0;
+    let mut failures = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        if let Some(reason) = output_collision(&inputs, &outputs, index) {
+            log::error!("Skipping {}, as {reason}", input.display());
+            failures.push(input.clone());
+            continue;
+        }
+
+        log::info!("Converting {}", input.display());
+        match convert(
+            input.clone(),
+            Command::Convert,
+            autoadd_path,
+            None,
+            args.u8_output,
+            args.verify,
+        ) {
+            Ok(()) => successes += 1,
+            Err(err) => {
+                log::error!("Failed to convert {}: {err}", input.display());
+                failures.push(input.clone());
+            }
+        }
+    }
+
+    log::info!("Converted {successes} files, {} failed", failures.len());
+    for failure in &failures {
+        log::error!("Failed: {}", failure.display());
+    }
+
+    if !failures.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "bin")]
+fn read_input(filename: &Path) -> Result<Vec<u8>, Error> {
+    wbz_converter::ensure_size(std::fs::metadata(filename)?.len())?;
+    Ok(std::fs::read(filename)?)
+}
 
-        (out, ".u8")
+#[cfg(feature = "bin")]
+fn decode(
+    mut in_buf: Vec<u8>,
+    autoadd_path: Option<&Path>,
+    u8_output: bool,
+) -> Result<Vec<u8>, Error> {
+    let mut progress = print_progress;
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_progress(&mut progress);
+
+    if wbz_converter::detect_format(&in_buf) != Some(Format::Wbz) {
+        wbz_converter::decode_wu8_with(&mut in_buf, options)?;
+        return Ok(in_buf);
+    }
+
+    if u8_output {
+        return wbz_converter::decode_wbz_with(Cursor::new(in_buf), options);
+    }
+
+    wbz_converter::decode_wbz_to_szs_with(
+        Cursor::new(in_buf),
+        options,
+        wbz_converter::Yaz0Compression::default(),
+    )
+}
+
+#[cfg(feature = "bin")]
+fn encode(mut in_buf: Vec<u8>, autoadd_path: Option<&Path>) -> Result<Vec<u8>, Error> {
+    let mut progress = print_progress;
+    let options = ConversionOptions::new()
+        .with_autoadd(autoadd_path)
+        .with_progress(&mut progress);
+
+    let mut out_file = Vec::new();
+    if in_buf.starts_with(YAZ0_MAGIC) {
+        wbz_converter::encode_szs_with(&in_buf, &mut out_file, options)?;
+    } else {
+        wbz_converter::encode_wbz_with(&mut in_buf, &mut out_file, options)?;
+    }
+
+    Ok(out_file)
+}
+
+/// Returns the extension of the file which `command` converts `in_buf` into, or `None` if the format is unknown.
+///
+/// Only the magic is read, so `in_buf` can be just the start of the file.
+#[cfg(feature = "bin")]
+fn output_extension(in_buf: &[u8], command: Command, u8_output: bool) -> Option<&'static str> {
+    let is_szs = in_buf.starts_with(YAZ0_MAGIC);
+    match (command, wbz_converter::detect_format(in_buf)) {
+        (Command::Encode, _) | (Command::Convert, Some(Format::U8)) => {
+            Some(Format::Wbz.extension())
+        }
+        (Command::Convert, None) if is_szs => Some(Format::Wbz.extension()),
+        (Command::Decode | Command::Convert, Some(Format::Wbz)) if !u8_output => Some("szs"),
+        (Command::Decode, _) | (Command::Convert, Some(Format::Wu8 | Format::Wbz)) => {
+            Some(Format::U8.extension())
+        }
+        _ => None,
+    }
+}
+
+/// Returns the path `input` would be converted into when converting a directory,
+/// or `None` if the format is unknown.
+#[cfg(feature = "bin")]
+fn planned_output(input: &Path, u8_output: bool) -> Result<Option<PathBuf>, Error> {
+    let mut magic = Vec::new();
+    std::fs::File::open(input)?
+        .take(WBZ_MAGIC_LEN)
+        .read_to_end(&mut magic)?;
+
+    let ext = output_extension(&magic, Command::Convert, u8_output);
+    Ok(ext.map(|ext| input.with_extension(ext)))
+}
+
+/// Returns why converting `inputs[index]` into `outputs[index]` would overwrite another file in the batch.
+#[cfg(feature = "bin")]
+fn output_collision(
+    inputs: &[PathBuf],
+    outputs: &[Option<PathBuf>],
+    index: usize,
+) -> Option<String> {
+    let output = outputs[index].as_ref()?;
+    if let Some(other) = inputs
+        .iter()
+        .enumerate()
+        .find(|&(other_index, other)| other_index != index && other == output)
+        .map(|(_, other)| other)
+    {
+        return Some(format!("the output would overwrite {}", other.display()));
+    }
+
+    let duplicates = outputs
+        .iter()
+        .filter(|other| other.as_ref() == Some(output));
+    if duplicates.count() > 1 {
+        return Some(format!(
+            "another file would also be converted into {}",
+            output.display()
+        ));
+    }
+
+    None
+}
+
+#[cfg(feature = "bin")]
+fn convert(
+    mut filename: PathBuf,
+    command: Command,
+    autoadd_path: Option<&Path>,
+    output: Option<&Path>,
+    u8_output: bool,
+    verify: bool,
+) -> Result<(), Error> {
+    let in_buf = read_input(&filename)?;
+    let original = verify.then(|| in_buf.clone());
+
+    let Some(ext) = output_extension(&in_buf, command, u8_output) else {
+        let found_magic = in_buf
+            .get(..4)
+            .and_then(|magic| magic.try_into().ok())
+            .unwrap_or_default();
+
+        return Err(Error::UnknownFormat { found_magic });
+    };
+
+    // Every conversion into a WBZ file is an encode, and every other conversion is a decode.
+    let out_file = if ext == Format::Wbz.extension() {
+        encode(in_buf, autoadd_path)?
+    } else {
+        decode(in_buf, autoadd_path, u8_output)?
     };
 
     // Setup new filename
-    let mut stem = filename.file_stem().unwrap().to_owned();
-    stem.push(ext);
-    filename.set_file_name(stem);
+    if let Some(output) = output {
+        filename = output.to_owned();
+    } else {
+        filename.set_extension(ext);
+    }
+
+    if let Some(original) = original {
+        verify_conversion(&original, &out_file, autoadd_path)?;
+        log::info!("{} verified", filename.display());
+    }
+
+    if output == Some(Path::new(STDOUT_PATH)) {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&out_file)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    write_output(&filename, &out_file)
+}
+
+/// Writes `data` to a temporary file next to `filename`, then renames it over `filename`,
+/// so an interrupted conversion never leaves a partially written output.
+#[cfg(feature = "bin")]
+fn write_output(filename: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(filename.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = filename.with_file_name(temp_name);
+
+    let result = std::fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&temp_path, filename));
+
+    if let Err(err) = result {
+        if let Err(remove_err) = std::fs::remove_file(&temp_path) {
+            log::debug!("Failed to remove {}: {remove_err}", temp_path.display());
+        }
+
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+/// Checks the WU8 file inside `original` is reproduced by converting `out_file` back.
+///
+/// # Errors
+/// Errors with [`Error::RoundTripMismatch`] at the first differing byte if it is not reproduced.
+#[cfg(feature = "bin")]
+fn verify_conversion(
+    original: &[u8],
+    out_file: &[u8],
+    autoadd_path: Option<&Path>,
+) -> Result<(), Error> {
+    let difference = match wbz_converter::detect_format(original) {
+        Some(Format::Wu8) => {
+            let mut wu8_file = out_file.to_vec();
+            wbz_converter::encode_wu8(&mut wu8_file, autoadd_path)?;
+
+            let mismatch = wbz_converter::assert_wu8_matches(&wu8_file, original).err();
+            mismatch.map(|mismatch| mismatch.offset)
+        }
+        Some(Format::Wbz) => {
+            // The WBZ compression may differ from the original, so only the WU8 files inside are compared.
+            let (mut u8_file, mut wbz_file) = (out_file.to_vec(), Vec::new());
+            if out_file.starts_with(YAZ0_MAGIC) {
+                wbz_converter::encode_szs(out_file, &mut wbz_file, autoadd_path)?;
+            } else {
+                wbz_converter::encode_wbz(&mut u8_file, &mut wbz_file, autoadd_path)?;
+            }
+
+            let wu8_file = wbz_converter::unwrap_wbz_as_wu8(Cursor::new(&wbz_file))?;
+            let original = wbz_converter::unwrap_wbz_as_wu8(Cursor::new(original))?;
+
+            let mismatch = wbz_converter::assert_wu8_matches(&wu8_file, &original).err();
+            mismatch.map(|mismatch| mismatch.offset)
+        }
+        // The original file was encoded, so the output is a WBZ file.
+        _ => wbz_converter::verify_wbz_roundtrip(out_file, autoadd_path)?,
+    };
+
+    match difference {
+        Some(offset) => Err(Error::RoundTripMismatch { offset }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "bin")]
+fn inspect(filename: &Path) -> Result<(), Error> {
+    let in_buf = read_input(filename)?;
+    let format = wbz_converter::detect_format(&in_buf);
+
+    // The node and string tables do not depend on the auto-add library, so the file data can be left undecoded.
+    let u8_file = match format {
+        Some(Format::Wbz) => wbz_converter::decode_wbz(Cursor::new(in_buf), None)?,
+        Some(Format::Wu8) => {
+            let mut u8_file = in_buf;
+            wbz_converter::decode_wu8(&mut u8_file, None)?;
+            u8_file
+        }
+        _ => in_buf,
+    };
+
+    let layout = wbz_converter::archive_layout(&u8_file)?;
+    println!("Format: {}", format.map_or("unknown", Format::extension));
+    println!("Nodes: {}", layout.node_count);
+    println!("Node table: {:#x}", layout.node_offset);
+    println!(
+        "String table: {:#x}..{:#x}",
+        layout.string_table_start, layout.string_table_end
+    );
+    println!("File data: {:#x}", layout.data_offset);
+    println!();
+    print!("{}", wbz_converter::format_tree(&u8_file)?);
 
-    std::fs::write(filename, out_file).expect("Unable to write finished file");
     Ok(())
 }
+
+#[cfg(feature = "bin")]
+fn verify(filename: &Path, autoadd_path: Option<&Path>) -> Result<(), Error> {
+    let in_buf = read_input(filename)?;
+
+    match wbz_converter::verify_wbz_roundtrip(&in_buf, autoadd_path)? {
+        None => log::info!("{} is unchanged after a round trip", filename.display()),
+        Some(offset) => return Err(Error::RoundTripMismatch { offset }),
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "bin"))]
+mod tests {
+    use super::*;
+
+    fn sample_wbz() -> Vec<u8> {
+        let mut u8_file = wbz_converter::U8Builder::new()
+            .add_file("./course.kmp", *b"kmp")
+            .add_file("./course_model.brres", *b"brres")
+            .finish()
+            .unwrap();
+
+        let mut wbz_file = Vec::new();
+        wbz_converter::encode_wbz(&mut u8_file, &mut wbz_file, None).unwrap();
+        wbz_file
+    }
+
+    #[test]
+    fn verify_decoded_wbz() {
+        let wbz_file = sample_wbz();
+
+        for u8_output in [false, true] {
+            let out_file = decode(wbz_file.clone(), None, u8_output).unwrap();
+            verify_conversion(&wbz_file, &out_file, None).unwrap();
+        }
+    }
+
+    #[test]
+    fn verify_mismatch_is_an_error() {
+        let wbz_file = sample_wbz();
+        let mut out_file = decode(wbz_file.clone(), None, true).unwrap();
+        *out_file.last_mut().unwrap() ^= 0xFF;
+
+        assert!(matches!(
+            verify_conversion(&wbz_file, &out_file, None),
+            Err(Error::RoundTripMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn batch_outputs_do_not_overwrite_inputs() {
+        let dir = std::env::temp_dir().join(format!("wbz_converter_{}_batch", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+
+        let u8_file = wbz_converter::U8Builder::new().finish().unwrap();
+        let szs_file = wbz_converter::decode_wbz_to_szs(
+            Cursor::new(sample_wbz()),
+            None,
+            wbz_converter::Yaz0Compression::none(),
+        )
+        .unwrap();
+
+        let mut wbz_file = Vec::new();
+        let mut wu8_file = u8_file.clone();
+        wbz_converter::encode_wbz(&mut u8_file.clone(), &mut wbz_file, None).unwrap();
+        wbz_converter::encode_wu8(&mut wu8_file, None).unwrap();
+
+        let files: [(&str, &[u8]); 7] = [
+            ("track.szs", &szs_file),
+            ("track.wbz", &wbz_file),
+            ("both.szs", &szs_file),
+            ("both.u8", &u8_file),
+            ("other.szs", &szs_file),
+            ("other.u8", &wu8_file),
+            ("single.wbz", &wbz_file),
+        ];
+
+        let inputs: Vec<_> = files
+            .iter()
+            .map(|(name, data)| {
+                let input = dir.join(name);
+                std::fs::write(&input, data).unwrap();
+                input
+            })
+            .collect();
+
+        let outputs: Vec<_> = inputs
+            .iter()
+            .map(|input| planned_output(input, false).unwrap())
+            .collect();
+
+        assert_eq!(outputs[0], Some(dir.join("track.wbz")));
+        assert_eq!(outputs[5], Some(dir.join("other.u8")));
+
+        let collisions: Vec<_> = (0..inputs.len())
+            .map(|index| output_collision(&inputs, &outputs, index).is_some())
+            .collect();
+
+        // The WU8 file is decoded in place, so only overwrites itself.
+        assert_eq!(collisions, [true, true, true, true, false, false, false]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn progress_percent_handles_large_and_empty_archives() {
+        let progress = |files_done, files_total| wbz_converter::Progress {
+            pass: 1,
+            files_done,
+            files_total,
+            bytes_done: 0,
+        };
+
+        assert_eq!(progress_percent(progress(u32::MAX, u32::MAX)), 100);
+        assert_eq!(progress_percent(progress(u32::MAX / 2, u32::MAX)), 49);
+        assert_eq!(progress_percent(progress(0, 0)), 100);
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| (*arg).to_owned()))
+    }
+
+    #[test]
+    fn unknown_option_is_an_error() {
+        assert_eq!(
+            parse(&["track.wbz", "--bogus"]).err().as_deref(),
+            Some("Unknown option --bogus")
+        );
+    }
+
+    #[test]
+    fn output_requires_a_value() {
+        assert_eq!(
+            parse(&["track.wbz", "--output"]).err().as_deref(),
+            Some("--output must be followed by a value")
+        );
+    }
+
+    #[test]
+    fn stdout_cannot_be_used_with_a_directory() {
+        let args = parse(&["tracks", "--stdout"]).unwrap();
+        assert_eq!(args.output.as_deref(), Some(Path::new(STDOUT_PATH)));
+        assert!(check_directory_args(&args).is_err());
+
+        let args = parse(&["decode", "tracks"]).unwrap();
+        assert!(check_directory_args(&args).is_err());
+
+        let args = parse(&["tracks", "--u8"]).unwrap();
+        assert!(check_directory_args(&args).is_ok());
+    }
+}
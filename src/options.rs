@@ -0,0 +1,326 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    converter::FileObserver, iterator::AutoAdd, AutoAddMode, PassOptions, Progress, WbzCompression,
+};
+
+/// A function called with each block of decompressed WU8 data and its offset in the WU8 file.
+type ChunkCallback<'a> = &'a mut dyn FnMut(&[u8], u64);
+
+/// The configuration for a conversion, see [`decode_wbz_with`](crate::decode_wbz_with) and
+/// [`encode_wbz_with`](crate::encode_wbz_with).
+///
+/// Every option defaults to the behaviour of the simple functions, such as [`decode_wbz`](crate::decode_wbz),
+/// without an auto-add library.
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use wbz_converter::{AutoAddMode, ConversionOptions};
+/// let mut progress = |progress| println!("{progress:?}");
+/// let options = ConversionOptions::new()
+///     .with_autoadd(Path::new("auto-add"))
+///     .with_autoadd_mode(AutoAddMode::Auto)
+///     .with_progress(&mut progress);
+/// ```
+pub struct ConversionOptions<'a> {
+    autoadd: Option<AutoAdd<'a>>,
+    autoadd_mode: AutoAddMode,
+    progress: Option<&'a mut dyn FnMut(Progress)>,
+    missing_autoadd: Option<&'a mut Vec<PathBuf>>,
+    observer: Option<FileObserver<'a>>,
+    starting_key: Option<u8>,
+    header_pass: bool,
+    check_overlaps: bool,
+    compression: WbzCompression,
+    on_chunk: Option<ChunkCallback<'a>>,
+    expected_checksum: Option<u32>,
+}
+
+impl Default for ConversionOptions<'_> {
+    fn default() -> Self {
+        Self {
+            autoadd: None,
+            autoadd_mode: AutoAddMode::Always,
+            progress: None,
+            missing_autoadd: None,
+            observer: None,
+            starting_key: None,
+            header_pass: true,
+            check_overlaps: false,
+            compression: WbzCompression::default(),
+            on_chunk: None,
+            expected_checksum: None,
+        }
+    }
+}
+
+impl<'a> ConversionOptions<'a> {
+    /// Creates options which skip the auto-add library, matching [`decode_wbz`](crate::decode_wbz) with `None`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the auto-add library directory, or skips the auto-add library if `None`.
+    ///
+    /// This replaces any resolver set by [`Self::with_resolver`].
+    #[must_use]
+    pub fn with_autoadd(mut self, autoadd_path: impl Into<Option<&'a Path>>) -> Self {
+        self.autoadd = autoadd_path.into().map(AutoAdd::Dir);
+        self
+    }
+
+    /// Reads auto-add files with `resolver` instead of from a directory, such as for a packed or remote library.
+    ///
+    /// `resolver` is called with the path of each file in the archive, such as `./course.kmp`,
+    /// and should return `None` if the file is not part of the auto-add library.
+    ///
+    /// This replaces any directory set by [`Self::with_autoadd`].
+    #[must_use]
+    pub fn with_resolver(
+        mut self,
+        resolver: &'a dyn Fn(&Path) -> std::io::Result<Option<Vec<u8>>>,
+    ) -> Self {
+        self.autoadd = Some(AutoAdd::Resolver(resolver));
+        self
+    }
+
+    /// Overrides the starting key, instead of deriving it from the file size.
+    ///
+    /// This is only needed for files produced by tools which derive the starting key differently.
+    #[must_use]
+    pub fn with_key(mut self, starting_key: u8) -> Self {
+        self.starting_key = Some(starting_key);
+        self
+    }
+
+    /// Sets a callback which is called after each file is processed.
+    #[must_use]
+    pub fn with_progress(mut self, progress: &'a mut dyn FnMut(Progress)) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Sets whether the auto-add library is used, see [`AutoAddMode`].
+    #[must_use]
+    pub fn with_autoadd_mode(mut self, autoadd_mode: AutoAddMode) -> Self {
+        self.autoadd_mode = autoadd_mode;
+        self
+    }
+
+    /// Pushes the path of each file that was looked up but not found in the auto-add library into `missing_autoadd`.
+    ///
+    /// Files which are not part of the auto-add library are expected to be missing, so this list is
+    /// most useful when compared against the files an archive is known to share with the library.
+    #[must_use]
+    pub fn with_missing_autoadd(mut self, missing_autoadd: &'a mut Vec<PathBuf>) -> Self {
+        self.missing_autoadd = Some(missing_autoadd);
+        self
+    }
+
+    /// Sets a callback which is called after each file is converted using the auto-add library.
+    ///
+    /// `observer` is called with the file name, the file size in bytes, and the derived key after
+    /// including that file, allowing inspection of which files contribute to the final key.
+    #[must_use]
+    pub fn with_observer(mut self, observer: &'a mut dyn FnMut(&str, u64, u8)) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Sets whether the header pass is performed, defaulting to `true`.
+    ///
+    /// The header pass encodes or decodes the node and string tables, so should only be skipped for
+    /// files produced by, or intended for, tools which leave them unencoded.
+    #[must_use]
+    pub fn with_header_pass(mut self, header_pass: bool) -> Self {
+        self.header_pass = header_pass;
+        self
+    }
+
+    /// Sets whether files with overlapping data are rejected, defaulting to `false`.
+    ///
+    /// Overlapping files would have their shared bytes XOR-ed multiple times, silently corrupting them,
+    /// so this check is useful for hand-crafted archives at the cost of an extra walk of the node table.
+    #[must_use]
+    pub fn with_overlap_check(mut self, check_overlaps: bool) -> Self {
+        self.check_overlaps = check_overlaps;
+        self
+    }
+
+    /// Sets the level of bzip2 compression, defaulting to [`WbzCompression::best`].
+    ///
+    /// This is only used by [`encode_wbz_with`](crate::encode_wbz_with).
+    #[must_use]
+    pub fn with_compression(mut self, compression: WbzCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets a callback which is called with each block of decompressed WU8 data and its offset in the WU8 file.
+    ///
+    /// This is only used when decoding WBZ files, see [`decode_wbz_with_chunks`](crate::decode_wbz_with_chunks).
+    #[must_use]
+    pub fn with_chunks(mut self, on_chunk: &'a mut dyn FnMut(&[u8], u64)) -> Self {
+        self.on_chunk = Some(on_chunk);
+        self
+    }
+
+    /// Checks the CRC-32 checksum of the WU8 file inside a WBZ file before decoding it.
+    ///
+    /// This is only used when decoding WBZ files, see [`decode_wbz_checked`](crate::decode_wbz_checked).
+    #[must_use]
+    pub fn with_checksum(mut self, expected_checksum: u32) -> Self {
+        self.expected_checksum = Some(expected_checksum);
+        self
+    }
+
+    pub(crate) fn compression(&self) -> WbzCompression {
+        self.compression
+    }
+
+    pub(crate) fn expected_checksum(&self) -> Option<u32> {
+        self.expected_checksum
+    }
+
+    pub(crate) fn on_chunk(&mut self, chunk: &[u8], offset: u64) {
+        if let Some(on_chunk) = &mut self.on_chunk {
+            on_chunk(chunk, offset);
+        }
+    }
+
+    pub(crate) fn pass_options(&mut self) -> PassOptions<'_> {
+        PassOptions {
+            autoadd: self.autoadd,
+            autoadd_mode: self.autoadd_mode,
+            progress: match &mut self.progress {
+                Some(progress) => Some(*progress),
+                None => None,
+            },
+            missing_autoadd: self.missing_autoadd.as_deref_mut(),
+            observer: match &mut self.observer {
+                Some(observer) => Some(*observer),
+                None => None,
+            },
+            starting_key: self.starting_key,
+            header_pass: self.header_pass,
+            check_overlaps: self.check_overlaps,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        decode_wbz, decode_wbz_into_with, decode_wu8_with, encode_wbz, encode_wbz_with, encode_wu8,
+        wbz_checksum, Error, U8Builder,
+    };
+
+    #[test]
+    fn options_cover_autoadd_callbacks() {
+        let dir =
+            std::env::temp_dir().join(format!("wbz_converter_{}_options", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("course.kmp"), b"auto-add kmp").unwrap();
+
+        let u8_file = U8Builder::new()
+            .add_file("./course.kmp", *b"track kmp")
+            .add_file("./course_model.brres", *b"brres")
+            .finish()
+            .unwrap();
+
+        let mut wu8_file = u8_file.clone();
+        encode_wu8(&mut wu8_file, Some(&dir)).unwrap();
+
+        let mut missing = Vec::new();
+        let mut observed = Vec::new();
+        let mut observer = |name: &str, size, _| observed.push((name.to_owned(), size));
+        let options = ConversionOptions::new()
+            .with_autoadd(dir.as_path())
+            .with_missing_autoadd(&mut missing)
+            .with_observer(&mut observer);
+
+        let mut decoded = wu8_file.clone();
+        decode_wu8_with(&mut decoded, options).unwrap();
+        assert_eq!(decoded, u8_file);
+        assert_eq!(missing, [PathBuf::from("./course_model.brres")]);
+        assert_eq!(observed, [("course.kmp".to_owned(), 9)]);
+
+        let resolver =
+            |path: &Path| Ok((path == Path::new("./course.kmp")).then(|| b"auto-add kmp".to_vec()));
+
+        let mut decoded = wu8_file.clone();
+        decode_wu8_with(
+            &mut decoded,
+            ConversionOptions::new().with_resolver(&resolver),
+        )
+        .unwrap();
+        assert_eq!(decoded, u8_file);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encode_wbz_with_matches_encode_wbz() {
+        let u8_file = U8Builder::new()
+            .add_file("./course.kmp", b"kmp".repeat(100))
+            .finish()
+            .unwrap();
+
+        let mut expected = Vec::new();
+        encode_wbz(&mut u8_file.clone(), &mut expected, None).unwrap();
+
+        let mut wbz_file = Vec::new();
+        encode_wbz_with(
+            &mut u8_file.clone(),
+            &mut wbz_file,
+            ConversionOptions::new(),
+        )
+        .unwrap();
+        assert_eq!(wbz_file, expected);
+
+        let mut wbz_file = Vec::new();
+        let options = ConversionOptions::new().with_compression(WbzCompression::fast());
+        encode_wbz_with(&mut u8_file.clone(), &mut wbz_file, options).unwrap();
+        assert_eq!(&wbz_file[16..20], b"BZh1");
+        assert_eq!(decode_wbz(Cursor::new(&wbz_file), None).unwrap(), u8_file);
+    }
+
+    #[test]
+    fn options_cover_wbz_decoding() {
+        let u8_file = U8Builder::new()
+            .add_file("./course.kmp", *b"kmp")
+            .add_file("./course_model.brres", *b"brres")
+            .finish()
+            .unwrap();
+
+        let mut wbz_file = Vec::new();
+        encode_wbz(&mut u8_file.clone(), &mut wbz_file, None).unwrap();
+        let checksum = wbz_checksum(Cursor::new(&wbz_file)).unwrap();
+
+        let (mut chunks, mut steps) = (Vec::new(), 0);
+        let mut on_chunk = |chunk: &[u8], offset| chunks.push((chunk.len(), offset));
+        let mut progress = |_| steps += 1;
+        let options = ConversionOptions::new()
+            .with_chunks(&mut on_chunk)
+            .with_checksum(checksum)
+            .with_progress(&mut progress);
+
+        let mut decoded = Vec::new();
+        let header = decode_wbz_into_with(Cursor::new(&wbz_file), options, &mut decoded).unwrap();
+        assert_eq!(decoded, u8_file);
+        assert_eq!(header.wu8_size as usize, u8_file.len());
+        assert_eq!(chunks, [(u8_file.len(), 0)]);
+        assert_eq!(steps, 2);
+
+        let options = ConversionOptions::new().with_checksum(!checksum);
+        assert!(matches!(
+            decode_wbz_into_with(Cursor::new(&wbz_file), options, &mut decoded),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+}
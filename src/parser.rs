@@ -1,125 +1,364 @@
-use std::io::{Read, Seek};
+use std::{
+    io::{Cursor, Read, Seek},
+    ops::Range,
+};
 
 use derivative::Derivative;
 
-use crate::{Error, U8Node};
+use crate::{Error, Format, U8Node, U8_MAGIC, WU8_MAGIC};
 
-#[derive(Derivative)]
+/// The header of a U8 or WU8 file, see [`read_header`](crate::read_header).
+#[derive(Derivative, Clone, Copy, PartialEq, Eq)]
 #[derivative(Debug)]
-pub(crate) struct U8Header {
+pub struct U8Header {
+    /// The magic identifying the file as U8 or WU8.
     pub magic: [u8; 4],
+    /// The offset of the node table, which directly follows the header.
     pub node_offset: u32,
+    /// The combined size of the node table and string table.
     pub meta_size: u32,
+    /// The offset of the first file's data.
     pub data_offset: u32,
+    /// The reserved bytes after the header fields, usually zero but sometimes used by tools for metadata.
+    pub padding: [u8; 16],
+    /// The byte order of the header and node table fields, detected from the magic.
+    pub endianness: Endianness,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) struct Parser<T: Read + Seek>(T);
+/// The byte order of the header and node table fields of a U8 or WU8 file.
+///
+/// Files from Nintendo and Wiimm's SZS Tools are big-endian, however some toolchains emit
+/// little-endian archives, which are detected by their byte-swapped magic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endianness {
+    #[default]
+    Big,
+    Little,
+}
 
-impl<T: Read + Seek> Parser<T> {
-    pub fn new(reader: T) -> Self {
-        Self(reader)
+impl Endianness {
+    /// Returns `magic` as it is stored in a file of this byte order.
+    #[must_use]
+    pub fn magic(self, magic: [u8; 4]) -> [u8; 4] {
+        match self {
+            Self::Big => magic,
+            Self::Little => u32::from_be_bytes(magic).to_le_bytes(),
+        }
     }
 
-    pub fn into_inner(self) -> T {
-        self.0
+    pub(crate) fn u32_from_bytes(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Self::Big => u32::from_be_bytes(bytes),
+            Self::Little => u32::from_le_bytes(bytes),
+        }
     }
 
-    pub fn position(&mut self) -> std::io::Result<u32> {
-        self.0
-            .stream_position()
-            .map(u32::try_from)
-            .map(Result::unwrap)
+    pub(crate) fn u32_to_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            Self::Big => value.to_be_bytes(),
+            Self::Little => value.to_le_bytes(),
+        }
     }
+}
 
-    pub fn set_position(&mut self, pos: u32) -> std::io::Result<()> {
-        self.0.seek(std::io::SeekFrom::Start(pos as u64)).map(drop)
+/// Parses the header of a U8 or WU8 file, see [`read_header`](crate::read_header).
+impl TryFrom<&[u8]> for U8Header {
+    type Error = Error;
+
+    fn try_from(file: &[u8]) -> Result<Self, Self::Error> {
+        crate::read_header(file)
     }
+}
 
-    pub fn read<const N: usize>(&mut self) -> Result<[u8; N], std::io::Error> {
-        let mut buf = [0; N];
-        self.0.read_exact(&mut buf)?;
-        Ok(buf)
+impl U8Header {
+    /// Checks the node and string tables lie within a file of `file_size` bytes,
+    /// and that the file data starts after them.
+    pub(crate) fn check_bounds(&self, file_size: usize) -> Result<(), Error> {
+        let meta_end = self.node_offset as u64 + self.meta_size as u64;
+        if meta_end > file_size as u64 {
+            return Err(Error::MetaSizeOutOfBounds {
+                node_offset: self.node_offset,
+                meta_size: self.meta_size,
+            });
+        }
+
+        if (self.data_offset as u64) < meta_end || self.data_offset as u64 > file_size as u64 {
+            return Err(Error::InconsistentHeader {
+                node_offset: self.node_offset,
+                meta_size: self.meta_size,
+                data_offset: self.data_offset,
+            });
+        }
+
+        Ok(())
     }
 
-    pub fn read_byte(&mut self) -> Result<u8, std::io::Error> {
-        self.read().map(|[b]| b)
+    /// Returns the range of the node and string tables, which must have been checked by [`Self::check_bounds`].
+    pub(crate) fn meta_range(&self) -> Range<usize> {
+        let start = self.node_offset as usize;
+        start..start + self.meta_size as usize
     }
 
-    pub fn read_bool(&mut self) -> Result<bool, Error> {
-        let byte = self.read::<1>()?;
-        match byte[0] {
-            0 => Ok(false),
-            1 => Ok(true),
-            _ => Err(Error::InvalidBool(byte[0])),
-        }
+    /// Returns the size of a node table containing `node_count` nodes, checking it lies within the metadata.
+    pub(crate) fn node_table_size(&self, node_count: u32) -> Result<u32, Error> {
+        node_count
+            .checked_mul(12)
+            .filter(|&size| size <= self.meta_size)
+            .ok_or(Error::NodeTableOutOfBounds { node_count })
     }
+}
 
-    pub fn read_u24(&mut self) -> Result<ux::u24, std::io::Error> {
-        let bytes = self.read::<3>()?;
-        let padded = [0, bytes[0], bytes[1], bytes[2]];
+/// Reads the header of a U8 or WU8 file, checking the node table directly follows it
+/// and the metadata lies within the file.
+pub(crate) fn read_checked_header(file: &[u8], expected_magic: [u8; 4]) -> Result<U8Header, Error> {
+    let mut reader = Parser::new(Cursor::new(file));
+    let header = reader.read_u8_header(expected_magic)?;
 
-        Ok(ux::u24::new(u32::from_be_bytes(padded)))
+    let start_pos = reader.position()?;
+    if start_pos != header.node_offset {
+        return Err(Error::HeaderOffsetMismatch {
+            expected: start_pos,
+            found: header.node_offset,
+        });
+    }
+
+    header.check_bounds(file.len())?;
+    Ok(header)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Parser<T: Read + Seek> {
+    reader: T,
+    endianness: Endianness,
+}
+
+impl<T: Read + Seek> Parser<T> {
+    pub fn new(reader: T) -> Self {
+        Self::with_endianness(reader, Endianness::Big)
+    }
+
+    pub fn with_endianness(reader: T, endianness: Endianness) -> Self {
+        Self { reader, endianness }
+    }
+
+    pub fn position(&mut self) -> Result<u32, Error> {
+        let position = self.reader.stream_position()?;
+        Ok(u32::try_from(position)?)
+    }
+
+    pub fn set_position(&mut self, pos: u32) -> std::io::Result<()> {
+        self.reader
+            .seek(std::io::SeekFrom::Start(pos as u64))
+            .map(drop)
+    }
+
+    pub fn read<const N: usize>(&mut self) -> Result<[u8; N], std::io::Error> {
+        let mut buf = [0; N];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
     }
 
     pub fn read_u32(&mut self) -> Result<u32, std::io::Error> {
         let bytes = self.read::<4>()?;
-        Ok(u32::from_be_bytes(bytes))
+        Ok(self.endianness.u32_from_bytes(bytes))
     }
 
-    /// Reads a null terminated string from the string table.
+    /// Reads the header of a U8 or WU8 file, detecting its byte order from `expected_magic`.
     ///
-    /// Does not change the position of the buffer, as that is reset after reading.
-    pub fn read_string(&mut self, table_start: u32, table_offset: u32) -> Result<String, Error> {
-        let starting_pos = self.position()?;
-        self.set_position(table_start + table_offset)?;
-
-        let mut out = String::new();
-        loop {
-            let byte = self.read_byte()?;
-            if byte == b'\0' {
-                self.set_position(starting_pos)?;
-                return Ok(out);
-            }
-
-            let byte_str = [byte];
-            out.push_str(std::str::from_utf8(&byte_str)?);
-        }
-    }
+    /// Every field read afterwards uses the detected byte order.
+    pub fn read_u8_header(&mut self, expected_magic: [u8; 4]) -> Result<U8Header, Error> {
+        let magic = self.read()?;
+        let endianness = [Endianness::Big, Endianness::Little]
+            .into_iter()
+            .find(|endianness| endianness.magic(expected_magic) == magic);
 
-    pub fn read_u8_header<const MAGIC: u32>(&mut self) -> Result<U8Header, Error> {
-        let header = U8Header {
-            magic: self.read()?,
+        let Some(endianness) = endianness else {
+            return Err(
+                if expected_magic == WU8_MAGIC && Format::from_magic(&magic) == Some(Format::U8) {
+                    Error::AlreadyDecoded
+                } else if expected_magic == U8_MAGIC {
+                    Error::InvalidU8Magic { found_magic: magic }
+                } else {
+                    Error::InvalidWU8Magic { found_magic: magic }
+                },
+            );
+        };
+
+        self.endianness = endianness;
+        Ok(U8Header {
+            magic,
             node_offset: self.read_u32()?,
             meta_size: self.read_u32()?,
             data_offset: self.read_u32()?,
-        };
-
-        // Skip the padding
-        self.read::<16>()?;
+            padding: self.read()?,
+            endianness,
+        })
+    }
 
-        let correct_magic = MAGIC.to_ne_bytes();
-        if header.magic == correct_magic {
-            Ok(header)
-        } else {
-            Err(Error::InvalidWU8Magic {
-                found_magic: header.magic,
-            })
+    /// Reads the root node, whose size is the number of nodes in the archive including itself.
+    pub fn read_root_node(&mut self) -> Result<U8Node, Error> {
+        let root_node = self.read_node()?;
+        if root_node.size == 0 {
+            return Err(Error::EmptyNodeTable);
         }
+
+        Ok(root_node)
     }
 
     pub fn read_node(&mut self) -> Result<U8Node, Error> {
+        // The type and name offset share a single field, with the type in the most significant byte.
+        let type_and_name = self.read_u32()?;
+        let [node_type, ..] = type_and_name.to_be_bytes();
+        let is_dir = match node_type {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::InvalidBool(node_type)),
+        };
+
         Ok(U8Node {
-            is_dir: self.read_bool()?,
-            name_offset: self.read_u24()?,
+            is_dir,
+            name_offset: ux::u24::new(type_and_name & 0xFF_FFFF),
             data_offset: self.read_u32()?,
             size: self.read_u32()?,
         })
     }
 }
 
-impl<T: AsRef<[u8]>> AsMut<T> for Parser<std::io::Cursor<T>> {
-    fn as_mut(&mut self) -> &mut T {
-        self.0.get_mut()
+impl<'a> Parser<Cursor<&'a [u8]>> {
+    /// Reads a null terminated string from the string table, borrowing it from the underlying buffer.
+    ///
+    /// Does not change the position of the buffer.
+    pub fn read_str(&self, table: Range<u32>, table_offset: u32) -> Result<&'a str, Error> {
+        let table_size = table.end.saturating_sub(table.start);
+        if table_offset >= table_size {
+            return Err(Error::InvalidStringOffset {
+                offset: table_offset,
+                table_size,
+            });
+        }
+
+        let buffer: &'a [u8] = self.reader.get_ref();
+        let start = table.start as usize + table_offset as usize;
+        let rest = buffer.get(start..table.end as usize).unwrap_or_default();
+
+        let terminator =
+            rest.iter()
+                .position(|&byte| byte == b'\0')
+                .ok_or(Error::UnterminatedString {
+                    offset: table_offset,
+                })?;
+
+        Ok(std::str::from_utf8(&rest[..terminator])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_wu8, encode_wu8, list_u8, read_header, U8Builder, U8_MAGIC, WU8_MAGIC};
+
+    #[test]
+    fn endianness_parses_same_archive() {
+        let mut builder = U8Builder::new();
+        builder
+            .add_file("./course.kmp", *b"kmp")
+            .add_file("./posteffect/posteffect.bfg", *b"bfg");
+
+        let big = builder.endianness(Endianness::Big).finish().unwrap();
+        let little = builder.endianness(Endianness::Little).finish().unwrap();
+        assert_eq!(&little[0..4], [0x2D, 0x38, 0xAA, 0x55]);
+        assert_eq!(&little[4..8], [0x20, 0, 0, 0]);
+
+        let big_header = read_header(&big).unwrap();
+        let little_header = read_header(&little).unwrap();
+        assert_eq!(big_header.endianness, Endianness::Big);
+        assert_eq!(little_header.endianness, Endianness::Little);
+        assert_eq!(
+            (
+                big_header.node_offset,
+                big_header.meta_size,
+                big_header.data_offset
+            ),
+            (
+                little_header.node_offset,
+                little_header.meta_size,
+                little_header.data_offset
+            )
+        );
+
+        assert_eq!(list_u8(&big).unwrap(), list_u8(&little).unwrap());
+
+        let mut wu8_file = little.clone();
+        encode_wu8(&mut wu8_file, None).unwrap();
+        decode_wu8(&mut wu8_file, None).unwrap();
+        assert_eq!(wu8_file, little);
+    }
+
+    #[test]
+    fn wrong_magic_names_expected_format() {
+        let u8_file = U8Builder::new().finish().unwrap();
+        let mut wu8_file = u8_file.clone();
+        encode_wu8(&mut wu8_file, None).unwrap();
+
+        let mut bad_u8_file = u8_file.clone();
+        bad_u8_file[3] ^= 1;
+        assert!(matches!(
+            read_checked_header(&bad_u8_file, U8_MAGIC),
+            Err(Error::InvalidU8Magic { found_magic }) if found_magic == [0x55, 0xAA, 0x38, 0x2C]
+        ));
+        assert!(matches!(
+            encode_wu8(&mut bad_u8_file, None),
+            Err(Error::InvalidU8Magic { .. })
+        ));
+
+        let mut bad_wu8_file = wu8_file.clone();
+        bad_wu8_file[3] ^= 1;
+        assert!(matches!(
+            read_checked_header(&bad_wu8_file, WU8_MAGIC),
+            Err(Error::InvalidWU8Magic { found_magic }) if found_magic == *b"WU8`"
+        ));
+        assert!(matches!(
+            decode_wu8(&mut bad_wu8_file, None),
+            Err(Error::InvalidWU8Magic { .. })
+        ));
+    }
+
+    fn read_str(buffer: &[u8], table: Range<u32>, offset: u32) -> Result<&str, Error> {
+        Parser::new(Cursor::new(buffer)).read_str(table, offset)
+    }
+
+    #[test]
+    fn read_str_decodes_utf8() {
+        let buffer = "\0café\0ñandú\0".as_bytes();
+
+        assert_eq!(read_str(buffer, 0..15, 1).unwrap(), "café");
+        assert_eq!(read_str(buffer, 0..15, 7).unwrap(), "ñandú");
+
+        // Starting within a multi-byte character leaves an invalid string.
+        assert!(matches!(
+            read_str(buffer, 0..15, 5),
+            Err(Error::InvalidString(_))
+        ));
+    }
+
+    #[test]
+    fn read_str_stays_within_table() {
+        let buffer = b"\0abc\0def\0file data\0";
+
+        assert_eq!(read_str(buffer, 0..9, 1).unwrap(), "abc");
+        assert_eq!(read_str(buffer, 0..9, 0).unwrap(), "");
+        assert!(matches!(
+            read_str(buffer, 0..9, 9),
+            Err(Error::InvalidStringOffset {
+                offset: 9,
+                table_size: 9
+            })
+        ));
+
+        // The last name is missing its terminator, so must not run on into the file data.
+        assert!(matches!(
+            read_str(buffer, 0..8, 5),
+            Err(Error::UnterminatedString { offset: 5 })
+        ));
     }
 }
@@ -1,22 +1,84 @@
 use log::{debug, info};
 
-use crate::U8Node;
+use crate::{Error, U8Node};
 
+/// Returns the starting key, which is the XOR of the four bytes of the file size.
+///
+/// This is used for the header pass, and combined with each auto-add file during pass one.
 pub(crate) fn derive_starting_key(size: u32) -> u8 {
     let [p0, p1, p2, p3] = size.to_le_bytes();
     let starting_key = p0 ^ p1 ^ p2 ^ p3;
 
-    info!("Derived starting key: {starting_key}");
+    info!("Derived starting key starting_key={starting_key}");
     starting_key
 }
 
+/// Returns the contribution of an auto-add file to the derived key, combined with the starting key using XOR.
+///
+/// For an auto-add file of `n` bytes, this is `data[n / 2] ^ data[n / 3] ^ data[n / 4]` using integer division.
+/// Every file found in the auto-add library contributes exactly once, so the derived key does not depend on
+/// the order files are processed in. Empty auto-add files are treated as missing, so never contribute.
+///
+/// Changing which files contribute changes the key used for every other file, so breaks compatibility
+/// with Wiimm's SZS Tools.
+pub(crate) fn derived_key_contribution(original_data: &[u8]) -> u8 {
+    let original_size = original_data.len();
+    original_data[original_size / 2]
+        ^ original_data[original_size / 3]
+        ^ original_data[original_size / 4]
+}
+
+/// Returns the indexes of the first two file nodes found with overlapping data.
+pub(crate) fn find_overlapping_nodes(nodes: &[U8Node]) -> Option<(u32, u32)> {
+    let mut files: Vec<(u32, &U8Node)> =
+        (0..).zip(nodes).filter(|(_, node)| !node.is_dir).collect();
+    files.sort_unstable_by_key(|(_, node)| node.data_offset);
+
+    files
+        .windows(2)
+        .find(|pair| {
+            let (_, first) = pair[0];
+            let (_, second) = pair[1];
+            first.data_offset as u64 + first.size as u64 > second.data_offset as u64
+        })
+        .map(|pair| (pair[0].0, pair[1].0))
+}
+
+/// XORs every byte of `data` with `key`, eight bytes at a time.
+pub(crate) fn xor_with_key(data: &mut [u8], key: u8) {
+    let wide_key = u64::from_ne_bytes([key; 8]);
+
+    let mut words = data.chunks_exact_mut(8);
+    for word in &mut words {
+        let value = u64::from_ne_bytes([
+            word[0], word[1], word[2], word[3], word[4], word[5], word[6], word[7],
+        ]);
+
+        word.copy_from_slice(&(value ^ wide_key).to_ne_bytes());
+    }
+
+    for byte in words.into_remainder() {
+        *byte ^= key;
+    }
+}
+
 pub(crate) fn perform_header_pass(file: &mut [u8], key: u8, start_pos: u32, meta_size: u32) {
-    debug!("Performing node header data pass");
-    file.as_mut()
-        .iter_mut()
-        .skip(start_pos as usize)
-        .take(meta_size as usize)
-        .for_each(|byte| *byte ^= key);
+    debug!("Performing node header data pass node_offset={start_pos} meta_size={meta_size}");
+    let start = (start_pos as usize).min(file.len());
+    let end = start.saturating_add(meta_size as usize).min(file.len());
+
+    xor_with_key(&mut file[start..end], key);
+}
+
+/// Returns the data of `node`, checking that it lies entirely within the file.
+fn node_data(wu8_raw: &mut [u8], node: U8Node) -> Result<&mut [u8], Error> {
+    let start = node.data_offset as usize;
+    let end = start + node.size as usize;
+
+    wu8_raw.get_mut(start..end).ok_or(Error::NodeOutOfBounds {
+        data_offset: node.data_offset,
+        size: node.size,
+    })
 }
 
 pub(crate) fn perform_pass_one(
@@ -24,27 +86,162 @@ pub(crate) fn perform_pass_one(
     original_data: &[u8],
     node: U8Node,
     starting_key: u8,
-) {
-    let mut original_index = 0;
-    let mut archive_index = 0;
-    while archive_index != node.size {
-        if original_index == original_data.len() {
-            original_index = 0;
+) -> Result<(), Error> {
+    node_data(wu8_raw, node)?
+        .iter_mut()
+        .zip(original_data.iter().cycle())
+        .for_each(|(enc, original)| *enc ^= starting_key ^ original);
+
+    Ok(())
+}
+
+pub(crate) fn perform_pass_two(
+    wu8_raw: &mut [u8],
+    node: U8Node,
+    derived_key: u8,
+) -> Result<(), Error> {
+    xor_with_key(node_data(wu8_raw, node)?, derived_key);
+    Ok(())
+}
+
+/// Performs pass two over every node in `nodes`, splitting the work across threads.
+///
+/// Falls back to [`perform_pass_two`] on each node if any data regions overlap,
+/// as the regions can then not be split into disjoint slices.
+#[cfg(feature = "parallel")]
+pub(crate) fn perform_pass_two_parallel(
+    wu8_raw: &mut [u8],
+    nodes: &mut [U8Node],
+    derived_key: u8,
+) -> Result<(), Error> {
+    for node in nodes.iter() {
+        node_data(wu8_raw, *node)?;
+    }
+
+    nodes.sort_unstable_by_key(|node| node.data_offset);
+    if find_overlapping_nodes(nodes).is_some() {
+        debug!("Data regions overlap, falling back to serial pass two");
+        for node in nodes {
+            perform_pass_two(wu8_raw, *node, derived_key)?;
         }
 
-        let archive_offset = node.data_offset + archive_index;
-        let enc = &mut wu8_raw[archive_offset as usize];
-        *enc ^= starting_key ^ original_data[original_index];
+        return Ok(());
+    }
+
+    let mut regions = Vec::with_capacity(nodes.len());
+    let mut rest = wu8_raw;
+    let mut rest_offset = 0;
+    for node in nodes.iter() {
+        let start = node.data_offset as usize;
+        let end = start + node.size as usize;
+
+        let (_, tail) = rest.split_at_mut(start - rest_offset);
+        let (region, tail) = tail.split_at_mut(end - start);
 
-        original_index += 1;
-        archive_index += 1;
+        regions.push(region);
+        rest = tail;
+        rest_offset = end;
     }
+
+    let threads = std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get);
+    let chunk_size = regions.len().div_ceil(threads).max(1);
+
+    debug!(
+        "Performing parallel pass two regions={} threads={threads}",
+        regions.len()
+    );
+    std::thread::scope(|scope| {
+        for chunk in regions.chunks_mut(chunk_size) {
+            scope.spawn(move || {
+                for region in chunk {
+                    xor_with_key(region, derived_key);
+                }
+            });
+        }
+    });
+
+    Ok(())
 }
 
-pub(crate) fn perform_pass_two(wu8_raw: &mut [u8], node: U8Node, derived_key: u8) {
-    wu8_raw
-        .iter_mut()
-        .skip(node.data_offset as usize)
-        .take(node.size as usize)
-        .for_each(|b| *b ^= derived_key);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_formulas() {
+        assert_eq!(derive_starting_key(0x1234_5678), 0x12 ^ 0x34 ^ 0x56 ^ 0x78);
+        assert_eq!(derive_starting_key(0), 0);
+
+        // Small files reuse the same bytes, as the indexes round down.
+        assert_eq!(derived_key_contribution(&[0x11]), 0x11);
+        assert_eq!(derived_key_contribution(&[0x11, 0x22]), 0x22);
+        assert_eq!(derived_key_contribution(&[0x11, 0x22, 0x33]), 0x11);
+
+        let data: Vec<u8> = (0..100).collect();
+        assert_eq!(derived_key_contribution(&data[..12]), 0x06 ^ 0x04 ^ 0x03);
+        assert_eq!(derived_key_contribution(&data), 0x32 ^ 0x21 ^ 0x19);
+    }
+
+    #[test]
+    fn xor_with_key_matches_scalar() {
+        let buffer: Vec<u8> = (0..=u8::MAX).cycle().take(64).collect();
+
+        for offset in 0..8 {
+            for len in 0..=17 {
+                let mut wide = buffer.clone();
+                let mut scalar = buffer.clone();
+
+                xor_with_key(&mut wide[offset..offset + len], 0xA5);
+                for byte in &mut scalar[offset..offset + len] {
+                    *byte ^= 0xA5;
+                }
+
+                assert_eq!(wide, scalar, "offset {offset} len {len}");
+            }
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn file_node(data_offset: u32, size: u32) -> U8Node {
+        U8Node {
+            is_dir: false,
+            name_offset: ux::u24::new(0),
+            data_offset,
+            size,
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn sample_file() -> Vec<u8> {
+        (0..=u8::MAX).cycle().take(0x2000).collect()
+    }
+
+    #[cfg(feature = "parallel")]
+    fn assert_parallel_matches_serial(nodes: &[U8Node]) {
+        let mut serial = sample_file();
+        for node in nodes {
+            perform_pass_two(&mut serial, *node, 0x5A).unwrap();
+        }
+
+        let mut parallel = sample_file();
+        perform_pass_two_parallel(&mut parallel, &mut nodes.to_vec(), 0x5A).unwrap();
+        assert_eq!(parallel, serial);
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn parallel_pass_two_matches_serial() {
+        // Unsorted, adjacent, empty, and unaligned regions across more files than threads.
+        let mut nodes: Vec<_> = (0..63)
+            .rev()
+            .map(|i| file_node(i * 0x80 + i % 7, 0x79))
+            .collect();
+        nodes.push(file_node(0x1F80, 0));
+        nodes.push(file_node(0x1F80, 0x80));
+        assert_eq!(find_overlapping_nodes(&nodes), None);
+        assert_parallel_matches_serial(&nodes);
+
+        // Overlapping regions fall back to the serial path.
+        assert_parallel_matches_serial(&[file_node(0x10, 0x40), file_node(0x20, 0x40)]);
+    }
 }
@@ -0,0 +1,304 @@
+use log::debug;
+
+use crate::Error;
+
+pub(crate) const YAZ0_MAGIC: [u8; 4] = *b"Yaz0";
+
+const HEADER_SIZE: usize = 16;
+const MAX_DISTANCE: usize = 0x1000;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 0xFF + 0x12;
+
+const HASH_BITS: u32 = 15;
+const NO_POSITION: u32 = u32::MAX;
+
+/// The level of effort used when compressing a U8 file into a [Yaz0](https://wiki.tockdom.com/wiki/YAZ0_(File_Format)) container.
+///
+/// Lower levels search fewer previous positions for back-references, so they encode faster
+/// but produce larger files. Level 0 disables back-references entirely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Yaz0Compression(u32);
+
+impl Yaz0Compression {
+    /// Creates a new compression spec with a specific numeric level (0-9).
+    #[must_use]
+    pub fn new(level: u32) -> Self {
+        Self(level.min(9))
+    }
+
+    /// Do not compress, only emit literal bytes.
+    #[must_use]
+    pub fn none() -> Self {
+        Self(0)
+    }
+
+    /// Optimize for the best speed of encoding.
+    #[must_use]
+    pub fn fast() -> Self {
+        Self(1)
+    }
+
+    /// Optimize for the size of data being encoded.
+    #[must_use]
+    pub fn best() -> Self {
+        Self(9)
+    }
+
+    /// Returns the compression level as an integer.
+    #[must_use]
+    pub fn level(self) -> u32 {
+        self.0
+    }
+
+    fn max_chain(self) -> usize {
+        match self.0 {
+            0 => 0,
+            level => 1 << (level + 3),
+        }
+    }
+}
+
+impl Default for Yaz0Compression {
+    fn default() -> Self {
+        Self::best()
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+struct Matcher<'a> {
+    data: &'a [u8],
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    max_chain: usize,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(data: &'a [u8], level: Yaz0Compression) -> Self {
+        Self {
+            data,
+            head: vec![NO_POSITION; 1 << HASH_BITS],
+            prev: vec![NO_POSITION; data.len()],
+            max_chain: level.max_chain(),
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.data.len() {
+            let bucket = hash(&self.data[pos..]);
+            self.prev[pos] = self.head[bucket];
+            self.head[bucket] = pos as u32;
+        }
+    }
+
+    /// Finds the longest back-reference for `pos`, returning `(distance, length)`.
+    fn find(&self, pos: usize) -> Option<(usize, usize)> {
+        if self.max_chain == 0 || pos + MIN_MATCH > self.data.len() {
+            return None;
+        }
+
+        let max_len = (self.data.len() - pos).min(MAX_MATCH);
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[hash(&self.data[pos..])];
+
+        for _ in 0..self.max_chain {
+            if candidate == NO_POSITION || pos - candidate as usize > MAX_DISTANCE {
+                break;
+            }
+
+            let start = candidate as usize;
+            let len = self.data[start..]
+                .iter()
+                .zip(&self.data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            if len >= MIN_MATCH && best.is_none_or(|(_, best_len)| len > best_len) {
+                best = Some((pos - start, len));
+                if len == max_len {
+                    break;
+                }
+            }
+
+            candidate = self.prev[start];
+        }
+
+        best
+    }
+}
+
+/// Compresses `data` into a Yaz0 container, including the 16 byte header.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn compress(data: &[u8], level: Yaz0Compression) -> Result<Vec<u8>, Error> {
+    let size: u32 = data.len().try_into()?;
+
+    debug!(
+        "Compressing {size} bytes into Yaz0 at level {}",
+        level.level()
+    );
+    let mut out = Vec::with_capacity(HEADER_SIZE + data.len() + data.len() / 8 + 1);
+    out.extend_from_slice(&YAZ0_MAGIC);
+    out.extend_from_slice(&size.to_be_bytes());
+    out.extend_from_slice(&[0; 8]);
+
+    let mut matcher = Matcher::new(data, level);
+    let mut pos = 0;
+    while pos < data.len() {
+        let group_header_pos = out.len();
+        out.push(0);
+
+        for bit in 0..8 {
+            if pos >= data.len() {
+                break;
+            }
+
+            if let Some((distance, len)) = matcher.find(pos) {
+                let distance = distance - 1;
+                if len >= 0x12 {
+                    out.push((distance >> 8) as u8);
+                    out.push(distance as u8);
+                    out.push((len - 0x12) as u8);
+                } else {
+                    out.push((((len - 2) << 4) | (distance >> 8)) as u8);
+                    out.push(distance as u8);
+                }
+
+                for matched_pos in pos..pos + len {
+                    matcher.insert(matched_pos);
+                }
+
+                pos += len;
+            } else {
+                out[group_header_pos] |= 0x80 >> bit;
+                out.push(data[pos]);
+
+                matcher.insert(pos);
+                pos += 1;
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Decompresses a Yaz0 container, returning the inner file.
+pub(crate) fn decompress(yaz0_file: &[u8]) -> Result<Vec<u8>, Error> {
+    let magic = yaz0_file
+        .get(0..4)
+        .ok_or(Error::InvalidYaz0Data)?
+        .try_into()
+        .map_err(|_| Error::InvalidYaz0Data)?;
+
+    if magic != YAZ0_MAGIC {
+        return Err(Error::InvalidYaz0Magic { found_magic: magic });
+    }
+
+    let size_bytes = yaz0_file.get(4..8).ok_or(Error::InvalidYaz0Data)?;
+    let size = u32::from_be_bytes(size_bytes.try_into().map_err(|_| Error::InvalidYaz0Data)?);
+
+    debug!("Decompressing Yaz0 into {size} bytes");
+    let input = yaz0_file.get(HEADER_SIZE..).ok_or(Error::InvalidYaz0Data)?;
+
+    // Each input byte can produce at most `MAX_MATCH` output bytes, so a larger size cannot be valid.
+    let size = size as usize;
+    let mut out = Vec::with_capacity(size.min(input.len().saturating_mul(MAX_MATCH)));
+
+    let mut input = input.iter();
+    let mut next_byte = || input.next().copied().ok_or(Error::InvalidYaz0Data);
+    while out.len() < size {
+        let group_header = next_byte()?;
+
+        for bit in 0..8 {
+            if out.len() >= size {
+                break;
+            }
+
+            if group_header & (0x80 >> bit) != 0 {
+                out.push(next_byte()?);
+                continue;
+            }
+
+            let b1 = next_byte()? as usize;
+            let b2 = next_byte()? as usize;
+
+            let distance = ((b1 & 0xF) << 8 | b2) + 1;
+            let len = match b1 >> 4 {
+                0 => next_byte()? as usize + 0x12,
+                n => n + 2,
+            };
+
+            if distance > out.len() {
+                return Err(Error::InvalidYaz0Data);
+            }
+
+            let start = out.len() - distance;
+            for i in start..start + len.min(size - out.len()) {
+                out.push(out[i]);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_data() -> Vec<u8> {
+        let mut data = b"course_model.brres".repeat(40);
+        data.extend((0..=255).cycle().take(1000));
+        data.extend([0; 600]);
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample_data();
+        for level in [0, 1, 5, 9] {
+            let compressed = compress(&data, Yaz0Compression::new(level)).unwrap();
+            assert_eq!(decompress(&compressed).unwrap(), data, "level {level}");
+        }
+
+        assert_eq!(
+            decompress(&compress(&[], Yaz0Compression::best()).unwrap()).unwrap(),
+            []
+        );
+    }
+
+    #[test]
+    fn truncated_input() {
+        let compressed = compress(&sample_data(), Yaz0Compression::best()).unwrap();
+
+        for len in [
+            0,
+            3,
+            8,
+            HEADER_SIZE,
+            compressed.len() / 2,
+            compressed.len() - 1,
+        ] {
+            assert!(
+                matches!(decompress(&compressed[..len]), Err(Error::InvalidYaz0Data)),
+                "len {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn size_is_not_trusted() {
+        let mut yaz0_file = YAZ0_MAGIC.to_vec();
+        yaz0_file.extend_from_slice(&u32::MAX.to_be_bytes());
+        yaz0_file.extend_from_slice(&[0; 8]);
+        yaz0_file.extend_from_slice(&[0xFF, 1, 2, 3]);
+
+        assert!(matches!(
+            decompress(&yaz0_file),
+            Err(Error::InvalidYaz0Data)
+        ));
+    }
+}